@@ -26,6 +26,9 @@ struct SwitchState {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Connection and pairing events (id52s hashed, see bhumi_node::set_redaction)
+    tracing_subscriber::fmt().with_target(false).init();
+
     let home = get_home();
     let state = SwitchState {
         is_on: AtomicBool::new(false),
@@ -83,7 +86,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Ok(json!({ "is_on": is_on }))
     });
 
-    // Built-in commands: node/info, invite/create, invite/list, invite/delete, peers/list
+    // Built-in commands: node/info, invite/create, invite/list, invite/delete, peers/list, peers/remove
     // Handshakes and preimage renewal are automatic
 
     println!("Connecting to relay...");
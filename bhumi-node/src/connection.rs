@@ -1,32 +1,134 @@
-//! Connection to relay (TLS disabled for dev)
+//! Connection to relay (TLS for `tls://` addresses, see `bhumi_proto::tls`)
+//!
+//! A connection is shared by reference: any number of `send`s can be
+//! outstanding at once, alongside a task receiving DELIVERs. There is no
+//! background reader; whichever call is waiting reads the next frame and
+//! hands it to its owner: a SEND_RESULT to the `send` with its request id,
+//! a DELIVER to the deliveries queue, anything else to the replies queue.
+//! Each waiter only takes what it asked for, so nothing read on someone
+//! else's behalf is lost. A `send` or `query_presence` that times out or is
+//! dropped gives up its place, so its late answer is discarded rather than
+//! queued forever or handed to the next caller.
 
-use tokio::net::TcpStream;
+use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, warn};
 
-use bhumi_proto::{Frame, Hello, IAm, Send as SendMsg, Deliver, Ack, SendResult, UpdateCommits, MSG_HELLO, MSG_DELIVER, MSG_SEND_RESULT};
-use bhumi_proto::async_io::{read_frame, write_frame};
-use fastn_id52::SecretKey;
+use bhumi_proto::{BhumiCodec, Hello, IAm, Send as SendMsg, Deliver, Ack, SendResult, UpdateCommits, RevokeCommits, Nack, RelayMessage};
+use bhumi_proto::{Presence, PresenceQuery, SignedPresence, presence::unix_now};
+use bhumi_proto::{CAP_COMMIT_EXPIRY, CAP_ENCRYPTION_REQUIRED, CAP_KEEPALIVE, CAP_NACK, CAP_PRESENCE, CAP_REQUEST_IDS};
+use bhumi_proto::{MSG_HELLO, MSG_PRESENCE_RESULT, RelayAddr, Transport};
+use bhumi_proto::redact::Id52;
+use fastn_id52::{PublicKey, SecretKey};
+
+/// Capabilities announced in I_AM
+const CLIENT_CAPABILITIES: u32 = CAP_KEEPALIVE | CAP_ENCRYPTION_REQUIRED | CAP_PRESENCE;
+
+/// How long past a SEND's deadline to wait for the relay's SEND_RESULT
+const DEADLINE_GRACE: Duration = Duration::from_secs(1);
+
+/// Most replies kept for callers that haven't taken them; past this the
+/// oldest is dropped, so a relay sending unasked-for frames can't grow it
+const MAX_QUEUED_REPLIES: usize = 64;
+
+/// Keepalive settings for a relay connection
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// How often to send KEEPALIVE while waiting for frames
+    pub interval: Duration,
+    /// Consider the relay dead if nothing (not even a KEEPALIVE reply) arrives for this long
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+/// Periodic PRESENCE publishing for the identity bound to a connection
+struct PresencePublisher {
+    secret_key: SecretKey,
+    relay_id52: [u8; 32],
+    ttl: Duration,
+    next_at: Instant,
+}
+
+/// The read side, with the timers serviced while waiting on it
+struct Reader {
+    frames: FramedRead<ReadHalf<Box<dyn Transport>>, BhumiCodec>,
+    keepalive: Keepalive,
+    last_seen: Instant,
+    next_ping: Instant,
+    presence: Option<PresencePublisher>,
+}
+
+/// Messages read for callers other than `send` and `receive_deliver`
+#[derive(Default)]
+struct Replies {
+    /// HELLO, PRESENCE_RESULT, anything unexpected, oldest first
+    queue: VecDeque<RelayMessage>,
+    /// PRESENCE_RESULTs still due for queries nobody waits for any more
+    abandoned_presence: usize,
+}
+
+impl Replies {
+    fn push(&mut self, msg: RelayMessage) {
+        if msg.msg_type() == MSG_PRESENCE_RESULT && self.abandoned_presence > 0 {
+            self.abandoned_presence -= 1;
+            debug!("Dropped PRESENCE_RESULT for an abandoned query");
+            return;
+        }
+        if self.queue.len() == MAX_QUEUED_REPLIES
+            && let Some(oldest) = self.queue.pop_front()
+        {
+            warn!("Reply queue full, dropped unclaimed 0x{:04x}", oldest.msg_type());
+        }
+        self.queue.push_back(msg);
+    }
+}
 
 /// A connection to a Bhumi relay
 pub struct Connection {
-    stream: TcpStream,
+    reader: Mutex<Reader>,
+    writer: Mutex<FramedWrite<WriteHalf<Box<dyn Transport>>, BhumiCodec>>,
+    /// DELIVERs not yet taken by `receive_deliver`, oldest first
+    delivers: std::sync::Mutex<VecDeque<Deliver>>,
+    /// Other messages, until a caller waiting for that type takes them
+    replies: std::sync::Mutex<Replies>,
+    /// `send`s awaiting their SEND_RESULT, by request id
+    pending: std::sync::Mutex<BTreeMap<u32, oneshot::Sender<SendResult>>>,
+    next_request_id: AtomicU32,
+    max_payload_size: usize,
+    /// Capabilities from the relay's HELLO (None until it arrives)
+    relay_capabilities: Option<u32>,
+    /// Identity the address requires the relay's HELLO to be signed by
+    expected_relay_id52: Option<PublicKey>,
+    /// Identity the relay signed its HELLO with, if it did
+    relay_id52: Option<PublicKey>,
 }
 
 impl Connection {
     /// Connect anonymously for send-only mode (no I_AM, sender stays anonymous)
     pub async fn connect_anonymous(addr: &str) -> std::io::Result<Self> {
-        let mut stream = TcpStream::connect(addr).await?;
+        let addr = RelayAddr::parse(addr)?;
+        let stream = addr.connect().await?;
+        let mut conn = Self::new(stream, addr.relay_id52);
 
         // Read HELLO (required to establish connection)
-        let frame = read_frame(&mut stream).await?;
-        if frame.msg_type != MSG_HELLO {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("expected HELLO, got 0x{:04x}", frame.msg_type),
-            ));
-        }
+        conn.read_hello().await?;
         // We don't send I_AM - sender remains anonymous to relay
 
-        Ok(Self { stream })
+        Ok(conn)
     }
 
     /// Connect to a relay with identity (for devices that need to receive messages)
@@ -35,29 +137,120 @@ impl Connection {
         secret_key: &SecretKey,
         commits: Vec<[u8; 32]>,
     ) -> std::io::Result<Self> {
-        let mut stream = TcpStream::connect(addr).await?;
+        let addr = RelayAddr::parse(addr)?;
+        let stream = addr.connect().await?;
+        let mut conn = Self::new(stream, addr.relay_id52);
 
         // Perform full handshake with I_AM
-        Self::handshake(&mut stream, secret_key, commits).await?;
+        conn.handshake(secret_key, commits).await?;
 
-        Ok(Self { stream })
+        Ok(conn)
     }
 
-    async fn handshake(
-        stream: &mut TcpStream,
+    fn new(stream: Box<dyn Transport>, expected_relay_id52: Option<PublicKey>) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let keepalive = Keepalive::default();
+        let now = Instant::now();
+        Self {
+            reader: Mutex::new(Reader {
+                frames: FramedRead::new(reader, BhumiCodec::new()),
+                keepalive,
+                last_seen: now,
+                next_ping: now + keepalive.interval,
+                presence: None,
+            }),
+            writer: Mutex::new(FramedWrite::new(writer, BhumiCodec::new())),
+            delivers: std::sync::Mutex::new(VecDeque::new()),
+            replies: std::sync::Mutex::new(Replies::default()),
+            pending: std::sync::Mutex::new(BTreeMap::new()),
+            next_request_id: AtomicU32::new(1),
+            max_payload_size: bhumi_proto::MAX_FRAME_SIZE,
+            relay_capabilities: None,
+            expected_relay_id52,
+            relay_id52: None,
+        }
+    }
+
+    /// Change keepalive settings (applies from the next ping)
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        let reader = self.reader.get_mut();
+        reader.keepalive = keepalive;
+        reader.next_ping = Instant::now() + keepalive.interval;
+    }
+
+    /// Publish a signed PRESENCE for `secret_key` now and every `ttl / 2`
+    /// while waiting for messages
+    ///
+    /// The relay is named by the id52 it signed its HELLO with. Does
+    /// nothing if the relay didn't announce CAP_PRESENCE or sent an unsigned
+    /// HELLO.
+    pub async fn publish_presence(
+        &mut self,
         secret_key: &SecretKey,
-        commits: Vec<[u8; 32]>,
+        ttl: Duration,
     ) -> std::io::Result<()> {
-        // Read HELLO
-        let frame = read_frame(stream).await?;
-        if frame.msg_type != MSG_HELLO {
+        let Some(relay_id52) = self.relay_id52 else {
+            return Ok(());
+        };
+        if !self.relay_supports(CAP_PRESENCE) {
+            return Ok(());
+        }
+        let reader = self.reader.get_mut();
+        reader.presence = Some(PresencePublisher {
+            secret_key: secret_key.clone(),
+            relay_id52: relay_id52.to_bytes(),
+            ttl,
+            next_at: Instant::now(),
+        });
+        let signed = Self::next_presence(reader)?;
+        self.write(signed).await
+    }
+
+    /// Sign a fresh PRESENCE, scheduling the next one at half the TTL
+    fn next_presence(reader: &mut Reader) -> std::io::Result<SignedPresence> {
+        let Some(publisher) = &mut reader.presence else {
+            return Err(std::io::Error::other("no PRESENCE to publish"));
+        };
+        let presence = Presence {
+            id52: publisher.secret_key.public_key().to_bytes(),
+            relay_id52: publisher.relay_id52,
+            issued_at: unix_now(),
+            ttl_secs: publisher.ttl.as_secs().try_into().unwrap_or(u32::MAX),
+        };
+        let signed = presence.sign(&publisher.secret_key)?;
+        publisher.next_at = Instant::now() + publisher.ttl / 2;
+        debug!("Publishing PRESENCE for {} (ttl {}s)", Id52(&signed.presence.id52), signed.presence.ttl_secs);
+        Ok(signed)
+    }
+
+    /// Ask the relay for the latest PRESENCE it holds for `id52`
+    ///
+    /// The result is whatever the relay returned: check it with
+    /// `SignedPresence::verify` before trusting it.
+    pub async fn query_presence(&self, id52: [u8; 32]) -> std::io::Result<Option<SignedPresence>> {
+        if !self.relay_supports(CAP_PRESENCE) {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("expected HELLO, got 0x{:04x}", frame.msg_type),
+                std::io::ErrorKind::Unsupported,
+                "relay does not support PRESENCE",
             ));
         }
+        let mut query = PendingQuery { conn: self, answered: false };
+        self.write(PresenceQuery { id52 }).await?;
+        let reply = self.next_reply(MSG_PRESENCE_RESULT).await?;
+        query.answered = true;
+        match reply {
+            RelayMessage::PresenceResult(result) => Ok(result.presence),
+            other => Err(unexpected("PRESENCE_RESULT", &other)),
+        }
+    }
 
-        let hello = Hello::from_bytes(&frame.payload)?;
+    async fn handshake(
+        &mut self,
+        secret_key: &SecretKey,
+        commits: Vec<[u8; 32]>,
+    ) -> std::io::Result<()> {
+        // Read HELLO
+        let hello = self.read_hello().await?;
 
         // Create I_AM
         let public_key = secret_key.public_key();
@@ -69,63 +262,477 @@ impl Connection {
         msg.extend_from_slice(&id52);
         let signature = secret_key.sign(&msg);
 
-        let i_am = IAm::new(id52, signature.to_bytes(), commits);
+        let i_am = IAm::new(id52, signature.to_bytes(), commits)
+            .with_capabilities(CLIENT_CAPABILITIES);
 
-        write_frame(stream, &Frame::i_am(&i_am)).await?;
+        debug!("Sending I_AM as {} ({} commits)", Id52(&id52), i_am.commits.len());
+        self.write(i_am).await?;
 
         Ok(())
     }
 
+    /// Read HELLO, check the relay identity if the address names one, and
+    /// adopt the relay's payload limit and capabilities
+    async fn read_hello(&mut self) -> std::io::Result<Hello> {
+        let hello = match self.next_reply(MSG_HELLO).await? {
+            RelayMessage::Hello(hello) => hello,
+            other => return Err(unexpected("HELLO", &other)),
+        };
+        if hello.version == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "relay speaks unsupported protocol version 0",
+            ));
+        }
+        self.relay_id52 = hello.verified_relay_id52();
+        if let Some(expected) = self.expected_relay_id52
+            && self.relay_id52 != Some(expected)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("relay HELLO is not signed by {}", expected),
+            ));
+        }
+        debug!(
+            "HELLO: v{}, caps 0x{:x}, max payload {}, relay id52 {}",
+            hello.version,
+            hello.capabilities,
+            hello.max_payload_size,
+            self.relay_id52.map_or("none".to_string(), |k| k.to_string())
+        );
+        self.relay_capabilities = Some(hello.capabilities);
+        let max_payload = hello.max_payload_size as usize;
+        self.reader.get_mut().frames.decoder_mut().set_max_payload(max_payload);
+        self.writer.get_mut().encoder_mut().set_max_payload(max_payload);
+        self.max_payload_size = max_payload;
+        Ok(hello)
+    }
+
+    /// Identity the relay signed its HELLO with (None for unsigned HELLOs)
+    ///
+    /// On its own this only says the relay has seen that signature; it is
+    /// bound to this connection when the address pins it (`?id52=`).
+    pub fn relay_id52(&self) -> Option<PublicKey> {
+        self.relay_id52
+    }
+
+    /// Largest frame payload the relay accepts (from its HELLO)
+    pub fn max_payload_size(&self) -> usize {
+        self.max_payload_size
+    }
+
+    /// Whether the relay announced `capability` (`CAP_*`) in its HELLO
+    pub fn relay_supports(&self, capability: u32) -> bool {
+        self.relay_capabilities.is_some_and(|caps| caps & capability == capability)
+    }
+
+    /// Version 1 relays don't answer KEEPALIVE, so don't ping or time out on them
+    fn keepalive_active(&self) -> bool {
+        self.relay_capabilities.is_none_or(|caps| caps & CAP_KEEPALIVE != 0)
+    }
+
+    async fn write(&self, msg: impl Into<RelayMessage>) -> std::io::Result<()> {
+        self.writer.lock().await.send(msg.into()).await
+    }
+
+    /// Read the next non-KEEPALIVE message and pass it on: a SEND_RESULT to
+    /// the `send` waiting for it, anything else to its queue. Pings the
+    /// relay while idle.
+    ///
+    /// Fails with `TimedOut` if the relay goes silent for longer than the
+    /// keepalive timeout, instead of waiting for the OS to notice a dead socket.
+    async fn read_one(&self, reader: &mut Reader) -> std::io::Result<()> {
+        let msg = loop {
+            tokio::select! {
+                frame = reader.frames.next() => {
+                    let frame = frame.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))??;
+                    reader.last_seen = Instant::now();
+                    match frame.decode()? {
+                        RelayMessage::Keepalive => {}
+                        msg => break msg,
+                    }
+                }
+                _ = tokio::time::sleep_until(reader.next_ping), if self.keepalive_active() => {
+                    self.write(RelayMessage::Keepalive).await?;
+                    reader.next_ping = Instant::now() + reader.keepalive.interval;
+                }
+                _ = tokio::time::sleep_until(reader.presence.as_ref().map_or(reader.next_ping, |p| p.next_at)), if reader.presence.is_some() => {
+                    let signed = Self::next_presence(reader)?;
+                    self.write(signed).await?;
+                }
+                _ = tokio::time::sleep_until(reader.last_seen + reader.keepalive.timeout), if self.keepalive_active() => {
+                    warn!("Relay silent for {}s, giving up on the connection", reader.keepalive.timeout.as_secs());
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "relay not responding to keepalive",
+                    ));
+                }
+            }
+        };
+
+        match msg {
+            RelayMessage::SendResult(result) => {
+                if result.request_id == 0 && self.relay_supports(CAP_REQUEST_IDS) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "SEND_RESULT without a request id",
+                    ));
+                }
+                let mut pending = self.pending.lock().unwrap();
+                // Relays that predate request ids answer SENDs in order
+                let waiter = match result.request_id {
+                    0 => pending.pop_first().map(|(_, tx)| tx),
+                    id => pending.remove(&id),
+                };
+                match waiter {
+                    Some(tx) => {
+                        let _ = tx.send(result);
+                    }
+                    None => debug!("SEND_RESULT for no pending SEND (request {})", result.request_id),
+                }
+            }
+            RelayMessage::Deliver(deliver) => self.delivers.lock().unwrap().push_back(deliver),
+            msg => self.replies.lock().unwrap().push(msg),
+        }
+        Ok(())
+    }
+
+    /// Wait until `take` finds something in a queue, reading frames
+    /// whenever no one else is
+    async fn next_queued<T>(&self, take: impl Fn() -> Option<T>) -> std::io::Result<T> {
+        loop {
+            if let Some(item) = take() {
+                return Ok(item);
+            }
+            let mut reader = self.reader.lock().await;
+            // Queued by whoever held the reader before us
+            if let Some(item) = take() {
+                return Ok(item);
+            }
+            self.read_one(&mut reader).await?;
+        }
+    }
+
+    /// Wait for the oldest reply of `msg_type`, leaving other replies queued
+    async fn next_reply(&self, msg_type: u16) -> std::io::Result<RelayMessage> {
+        self.next_queued(|| {
+            let queue = &mut self.replies.lock().unwrap().queue;
+            let index = queue.iter().position(|msg| msg.msg_type() == msg_type)?;
+            queue.remove(index)
+        })
+        .await
+    }
+
     /// Send a message to another device and wait for response
+    ///
+    /// Other `send`s (and `receive_deliver`) may run concurrently on the
+    /// same connection; each SEND carries a request id its SEND_RESULT is
+    /// matched by.
     pub async fn send(
-        &mut self,
+        &self,
+        to_id52: [u8; 32],
+        preimage: [u8; 32],
+        payload: Vec<u8>,
+    ) -> std::io::Result<SendResult> {
+        self.send_inner(to_id52, preimage, payload, 0).await
+    }
+
+    /// `send`, asking the relay to give up on the recipient after `deadline`
+    ///
+    /// The relay clamps the deadline to its configured range and answers
+    /// SEND_ERR_TIMEOUT once it passes. Relays that predate deadlines wait
+    /// their default instead, so the wait is also bounded here: a second
+    /// past `deadline` this fails with `TimedOut`.
+    pub async fn send_with_deadline(
+        &self,
+        to_id52: [u8; 32],
+        preimage: [u8; 32],
+        payload: Vec<u8>,
+        deadline: Duration,
+    ) -> std::io::Result<SendResult> {
+        let deadline_ms = deadline.as_millis().clamp(1, u32::MAX.into()) as u32;
+        let send = self.send_inner(to_id52, preimage, payload, deadline_ms);
+        match tokio::time::timeout(deadline + DEADLINE_GRACE, send).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no SEND_RESULT by the deadline")),
+        }
+    }
+
+    #[tracing::instrument(name = "send", skip_all, fields(to = %Id52(&to_id52)))]
+    async fn send_inner(
+        &self,
         to_id52: [u8; 32],
         preimage: [u8; 32],
         payload: Vec<u8>,
+        deadline_ms: u32,
     ) -> std::io::Result<SendResult> {
+        let request_id = match self.next_request_id.fetch_add(1, Ordering::Relaxed) {
+            0 => self.next_request_id.fetch_add(1, Ordering::Relaxed),
+            id => id,
+        };
+        let (tx, mut rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id, tx);
+        let _pending = PendingSend { conn: self, request_id };
+
         let send = SendMsg {
             to_id52,
             preimage,
             payload,
+            request_id,
+            deadline_ms,
         };
+        self.write(send).await?;
 
-        write_frame(&mut self.stream, &Frame::send(&send)).await?;
-
-        // Wait for SEND_RESULT
-        let frame = read_frame(&mut self.stream).await?;
-        if frame.msg_type != MSG_SEND_RESULT {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("expected SEND_RESULT, got 0x{:04x}", frame.msg_type),
-            ));
-        }
-
-        SendResult::from_bytes(&frame.payload)
+        // Wait for SEND_RESULT, reading frames ourselves whenever no one else is
+        let result = loop {
+            tokio::select! {
+                biased;
+                result = &mut rx => break result.map_err(|_| std::io::Error::other("SEND_RESULT dropped"))?,
+                mut reader = self.reader.lock() => {
+                    // It may have arrived while we waited for the reader
+                    if let Ok(result) = rx.try_recv() {
+                        break result;
+                    }
+                    self.read_one(&mut reader).await?;
+                }
+            }
+        };
+        debug!("SEND_RESULT: status {} ({} bytes)", result.status, result.payload.len());
+        Ok(result)
     }
 
     /// Send an ACK response to a delivered message
-    pub async fn send_ack(&mut self, msg_id: u32, payload: Vec<u8>) -> std::io::Result<()> {
+    pub async fn send_ack(&self, msg_id: u32, payload: Vec<u8>) -> std::io::Result<()> {
         let ack = Ack { msg_id, payload };
-        write_frame(&mut self.stream, &Frame::ack(&ack)).await
+        self.write(ack).await
+    }
+
+    /// Refuse a delivered message with a NACK_* reason
+    ///
+    /// Relays without CAP_NACK get an empty ACK instead, which their sender
+    /// receives as an empty response.
+    pub async fn send_nack(&self, msg_id: u32, reason: u8) -> std::io::Result<()> {
+        if !self.relay_supports(CAP_NACK) {
+            return self.send_ack(msg_id, Vec::new()).await;
+        }
+        self.write(Nack { msg_id, reason }).await
     }
 
     /// Send UPDATE_COMMITS to add new commits while connected
-    pub async fn update_commits(&mut self, commits: Vec<[u8; 32]>) -> std::io::Result<()> {
-        let update = UpdateCommits { commits };
-        write_frame(&mut self.stream, &Frame::update_commits(&update)).await
+    pub async fn update_commits(&self, commits: Vec<[u8; 32]>) -> std::io::Result<()> {
+        let update = UpdateCommits::new(commits);
+        self.write(update).await
     }
 
-    /// Wait for and receive a delivered message
-    pub async fn receive_deliver(&mut self) -> std::io::Result<Deliver> {
-        let frame = read_frame(&mut self.stream).await?;
+    /// Send UPDATE_COMMITS for commits the relay should drop after `ttl`
+    ///
+    /// Relays without CAP_COMMIT_EXPIRY ignore the TTL and keep them for
+    /// as long as this connection lasts.
+    pub async fn update_expiring_commits(&self, commits: Vec<[u8; 32]>, ttl: Duration) -> std::io::Result<()> {
+        let ttl_secs = ttl.as_secs().clamp(1, u32::MAX.into()) as u32;
+        let update = UpdateCommits::new(commits).with_ttl(ttl_secs);
+        self.write(update).await
+    }
 
-        if frame.msg_type != MSG_DELIVER {
+    /// Send REVOKE_COMMITS to withdraw commits that haven't been used
+    pub async fn revoke_commits(&self, commits: Vec<[u8; 32]>) -> std::io::Result<()> {
+        if !self.relay_supports(CAP_COMMIT_EXPIRY) {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("expected DELIVER, got 0x{:04x}", frame.msg_type),
+                std::io::ErrorKind::Unsupported,
+                "relay does not support REVOKE_COMMITS",
             ));
         }
+        self.write(RevokeCommits { commits }).await
+    }
+
+    /// Wait for and receive a delivered message
+    pub async fn receive_deliver(&self) -> std::io::Result<Deliver> {
+        self.next_queued(|| self.delivers.lock().unwrap().pop_front()).await
+    }
+}
+
+/// Forgets a `send`'s request id when it completes, times out or is dropped
+struct PendingSend<'a> {
+    conn: &'a Connection,
+    request_id: u32,
+}
+
+impl Drop for PendingSend<'_> {
+    fn drop(&mut self) {
+        // Relays without request ids answer in order, so an unanswered
+        // entry stays to take its late SEND_RESULT (and discard it, the
+        // receiver being gone) rather than let the next `send` take it
+        if self.conn.relay_supports(CAP_REQUEST_IDS) {
+            self.conn.pending.lock().unwrap().remove(&self.request_id);
+        }
+    }
+}
+
+/// Discards a `query_presence`'s PRESENCE_RESULT if it is dropped or times
+/// out before taking it
+struct PendingQuery<'a> {
+    conn: &'a Connection,
+    answered: bool,
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        if self.answered {
+            return;
+        }
+        let mut replies = self.conn.replies.lock().unwrap();
+        match replies.queue.iter().position(|msg| msg.msg_type() == MSG_PRESENCE_RESULT) {
+            Some(index) => {
+                replies.queue.remove(index);
+            }
+            None => replies.abandoned_presence += 1,
+        }
+    }
+}
+
+fn unexpected(expected: &str, got: &RelayMessage) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("expected {}, got 0x{:04x}", expected, got.msg_type()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bhumi_proto::PresenceResult;
+    use tokio::net::TcpListener;
+
+    /// A DELIVER that arrives while a PRESENCE_QUERY waits for its answer
+    /// is kept for `receive_deliver`
+    #[tokio::test]
+    async fn replies_and_deliveries_are_queued_apart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            let mut reader = FramedRead::new(reader, BhumiCodec::new());
+            let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+            writer.send(RelayMessage::from(Hello::new(1, 64 * 1024, CAP_PRESENCE))).await.unwrap();
+            while let Some(Ok(frame)) = reader.next().await {
+                if let Ok(RelayMessage::PresenceQuery(_)) = frame.decode() {
+                    writer.send(RelayMessage::from(Deliver { msg_id: 7, preimage: [1; 32], payload: b"hi".to_vec() })).await.unwrap();
+                    writer.send(RelayMessage::from(PresenceResult { presence: None })).await.unwrap();
+                }
+            }
+        });
+
+        let conn = Connection::connect_anonymous(&addr.to_string()).await.unwrap();
+        assert_eq!(conn.query_presence([2; 32]).await.unwrap(), None);
+        let deliver = conn.receive_deliver().await.unwrap();
+        assert_eq!((deliver.msg_id, deliver.payload), (7, b"hi".to_vec()));
+    }
+
+    /// The late answer to a query that timed out is discarded, not taken
+    /// by the next query
+    #[tokio::test]
+    async fn abandoned_query_is_not_answered_late() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = fastn_id52::SecretKey::generate();
+        let presence = Presence { id52: [2; 32], relay_id52: relay.public_key().to_bytes(), issued_at: unix_now(), ttl_secs: 60 };
+        let signed = presence.sign(&relay).unwrap();
+        let expected = signed.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            let mut reader = FramedRead::new(reader, BhumiCodec::new());
+            let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+            writer.send(RelayMessage::from(Hello::new(1, 64 * 1024, CAP_PRESENCE))).await.unwrap();
+            let mut queries = 0;
+            while let Some(Ok(frame)) = reader.next().await {
+                if let Ok(RelayMessage::PresenceQuery(_)) = frame.decode() {
+                    queries += 1;
+                    // The first query is only answered along with the second
+                    if queries == 2 {
+                        writer.send(RelayMessage::from(PresenceResult { presence: None })).await.unwrap();
+                        writer.send(RelayMessage::from(PresenceResult { presence: Some(signed.clone()) })).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let conn = Connection::connect_anonymous(&addr.to_string()).await.unwrap();
+        let first = tokio::time::timeout(Duration::from_millis(100), conn.query_presence([1; 32])).await;
+        assert!(first.is_err());
+        assert_eq!(conn.query_presence([2; 32]).await.unwrap(), Some(expected));
+        assert!(conn.replies.lock().unwrap().queue.is_empty());
+    }
+
+    /// A relay that announced request ids can't leave them out
+    #[tokio::test]
+    async fn send_result_without_request_id_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            let mut reader = FramedRead::new(reader, BhumiCodec::new());
+            let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+            writer.send(RelayMessage::from(Hello::new(1, 64 * 1024, CAP_REQUEST_IDS))).await.unwrap();
+            while let Some(Ok(frame)) = reader.next().await {
+                if let Ok(RelayMessage::Send(_)) = frame.decode() {
+                    writer.send(RelayMessage::from(SendResult::success(b"ok".to_vec()))).await.unwrap();
+                }
+            }
+        });
+
+        let conn = Connection::connect_anonymous(&addr.to_string()).await.unwrap();
+        let err = conn.send([1; 32], [2; 32], b"hi".to_vec()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(conn.pending.lock().unwrap().is_empty());
+    }
+
+    /// Replies nobody takes are capped, dropping the oldest
+    #[test]
+    fn reply_queue_is_capped() {
+        let mut replies = Replies::default();
+        for _ in 0..MAX_QUEUED_REPLIES + 3 {
+            replies.push(RelayMessage::Keepalive);
+        }
+        replies.push(RelayMessage::from(PresenceResult { presence: None }));
+        assert_eq!(replies.queue.len(), MAX_QUEUED_REPLIES);
+        assert_eq!(replies.queue.back().unwrap().msg_type(), MSG_PRESENCE_RESULT);
+    }
+
+    /// A relay that stops answering KEEPALIVE is given up on once the
+    /// keepalive timeout passes, not when the OS notices
+    #[tokio::test]
+    async fn silent_relay_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (pings_tx, mut pings_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            let mut reader = FramedRead::new(reader, BhumiCodec::new());
+            let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+            writer.send(RelayMessage::from(Hello::new(1, 64 * 1024, CAP_KEEPALIVE))).await.unwrap();
+            // Reads the pings but never answers them
+            while let Some(Ok(frame)) = reader.next().await {
+                if let Ok(RelayMessage::Keepalive) = frame.decode() {
+                    let _ = pings_tx.send(());
+                }
+            }
+        });
+
+        let mut conn = Connection::connect_anonymous(&addr.to_string()).await.unwrap();
+        let keepalive = Keepalive { interval: Duration::from_millis(100), timeout: Duration::from_millis(300) };
+        conn.set_keepalive(keepalive);
 
-        Deliver::from_bytes(&frame.payload)
+        let started = Instant::now();
+        let err = tokio::time::timeout(Duration::from_secs(5), conn.receive_deliver())
+            .await
+            .expect("still waiting on a silent relay")
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < keepalive.timeout + Duration::from_millis(500));
+        assert!(pings_rx.try_recv().is_ok());
     }
 }
@@ -1,7 +1,7 @@
 //! Identity management - stores keys in BHUMI_HOME
 
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use fastn_id52::{SecretKey, PublicKey};
 
@@ -23,7 +23,7 @@ pub fn bhumi_home() -> PathBuf {
 }
 
 /// Load or create device identity from the given home directory
-pub fn load_or_create(home: &PathBuf) -> (SecretKey, PublicKey) {
+pub fn load_or_create(home: &Path) -> (SecretKey, PublicKey) {
     let key_path = home.join("identity.key");
 
     if key_path.exists() {
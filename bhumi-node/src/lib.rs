@@ -53,13 +53,21 @@
 //!     println!("Status: {:?}", result);
 //! }
 //! ```
+//!
+//! # Logging
+//!
+//! The crate logs through `tracing`: install a subscriber to see it. `run`
+//! opens a `node` span and a `msg` span (with the relay's msg_id) per
+//! delivery. id52s are printed as [`set_redaction`] allows (hashed by
+//! default); preimages and payloads are never logged.
 
 mod connection;
 mod identity;
 mod node;
 mod state;
 
-pub use connection::Connection;
+pub use connection::{Connection, Keepalive};
+pub use bhumi_proto::{RelayAddr, RelayPin, Transport};
 pub use identity::{load_or_create_identity, load_or_create, bhumi_home};
 pub use node::{Node, NodeConfig, CommandHandler};
 pub use state::{
@@ -75,9 +83,15 @@ pub use bhumi_proto::{
     HandshakeInit, HandshakeComplete, SendResult,
     HANDSHAKE_ACCEPTED, HANDSHAKE_REJECTED,
     SEND_OK, SEND_ERR_NOT_CONNECTED, SEND_ERR_INVALID_PREIMAGE,
-    SEND_ERR_TIMEOUT, SEND_ERR_DISCONNECTED,
+    SEND_ERR_TIMEOUT, SEND_ERR_DISCONNECTED, SEND_ERR_NOT_ENCRYPTED,
+    SEND_ERR_RATE_LIMITED, SEND_ERR_TOO_MANY_IN_FLIGHT, SEND_ERR_BUSY, SEND_ERR_REFUSED,
+    NACK_MALFORMED, NACK_BUSY, NACK_UNKNOWN_PREIMAGE,
+    PROTOCOL_VERSION, CAP_KEEPALIVE, CAP_ENCRYPTION_REQUIRED, CAP_PRESENCE, CAP_COMMIT_EXPIRY, CAP_NACK,
+    Presence, PresenceError, SignedPresence,
     DEV_HANDSHAKE_INIT, parse_device_msg_type,
 };
+pub use bhumi_proto::crypto::{self, CryptoError};
+pub use bhumi_proto::redact::{Id52, Redaction, set_redaction};
 
 /// Request message format for commands
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -130,20 +144,30 @@ pub struct IncomingMessage {
     pub msg_id: u32,
     pub preimage: [u8; 32],
     pub msg_type: Option<u8>,
+    /// Decrypted payload
     pub payload: Vec<u8>,
 }
 
-impl Connection {
-    /// Receive the next incoming message
-    pub async fn receive(&mut self) -> std::io::Result<IncomingMessage> {
-        let deliver = self.receive_deliver().await?;
-        let msg_type = parse_device_msg_type(&deliver.payload);
+impl IncomingMessage {
+    /// Decrypt a DELIVER sealed to `secret_key`
+    pub fn open(deliver: bhumi_proto::Deliver, secret_key: &SecretKey) -> Result<Self, CryptoError> {
+        let payload = crypto::open(&secret_key.to_bytes(), &deliver.payload)?;
+        let msg_type = parse_device_msg_type(&payload);
 
-        Ok(IncomingMessage {
+        Ok(Self {
             msg_id: deliver.msg_id,
             preimage: deliver.preimage,
             msg_type,
-            payload: deliver.payload,
+            payload,
         })
     }
 }
+
+impl Connection {
+    /// Receive and decrypt the next incoming message
+    pub async fn receive(&self, secret_key: &SecretKey) -> std::io::Result<IncomingMessage> {
+        let deliver = self.receive_deliver().await?;
+        IncomingMessage::open(deliver, secret_key)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
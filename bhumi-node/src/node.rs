@@ -2,16 +2,25 @@
 
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use bhumi_proto::presence::unix_now;
+use bhumi_proto::redact::Id52;
+use tracing::{Instrument, debug, info, info_span, warn};
 
 use crate::{
-    Connection, CommandContext, Request, Response,
+    Connection, Keepalive, CommandContext, IncomingMessage, Request, Response,
     DeviceState, PeerRecord, PeerRole, PreimageLookup,
     SecretKey, PublicKey, JsonValue, json,
     HandshakeInit, HandshakeComplete, HANDSHAKE_ACCEPTED, HANDSHAKE_REJECTED,
     DEV_HANDSHAKE_INIT,
-    load_or_create, create_invite_token, parse_invite_token,
+    load_or_create, create_invite_token, parse_invite_token, crypto,
+    Presence, CAP_COMMIT_EXPIRY, NACK_MALFORMED, NACK_UNKNOWN_PREIMAGE,
 };
 
+/// Default lifetime of the PRESENCE `run` publishes (re-published at half of it)
+const DEFAULT_PRESENCE_TTL: Duration = Duration::from_secs(300);
+
 /// Node configuration
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct NodeConfig {
@@ -44,6 +53,11 @@ pub struct Node<S: Send + Sync + 'static = ()> {
     #[allow(dead_code)]
     config_path: PathBuf,
     relay_addr: Option<String>,
+    keepalive: Keepalive,
+    presence_ttl: Option<Duration>,
+    /// Commits of invites and peers removed by a command, to withdraw
+    /// from the relay once it has run (or from the next relay that can)
+    revoked: Vec<[u8; 32]>,
     handlers: HashMap<String, CommandHandler<S>>,
     app_state: Option<S>,
 }
@@ -83,6 +97,9 @@ impl<S: Send + Sync + 'static> Node<S> {
             config,
             config_path,
             relay_addr: None,
+            keepalive: Keepalive::default(),
+            presence_ttl: Some(DEFAULT_PRESENCE_TTL),
+            revoked: Vec::new(),
             handlers: HashMap::new(),
             app_state: Some(app_state),
         }
@@ -111,6 +128,18 @@ impl<S: Send + Sync + 'static> Node<S> {
         self.handlers.insert(name.to_string(), Box::new(handler));
     }
 
+    /// Set how often `run` pings the relay and how long it waits before
+    /// treating a silent relay as dead
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = keepalive;
+    }
+
+    /// Set the TTL of the PRESENCE `run` publishes to its relay, or `None`
+    /// to not publish presence at all
+    pub fn set_presence_ttl(&mut self, ttl: Option<Duration>) {
+        self.presence_ttl = ttl;
+    }
+
     /// Create an invite for another node to pair with us
     pub fn create_invite(&mut self, alias: &str, role: PeerRole) -> String {
         let (invite, _commit) = self.state.create_invite(alias, role);
@@ -165,20 +194,23 @@ impl<S: Send + Sync + 'static> Node<S> {
         self.save();
 
         // Connect anonymously (sender identity not revealed to relay)
-        let mut conn = Connection::connect_anonymous(relay_addr).await?;
+        let conn = Connection::connect_anonymous(relay_addr).await?;
 
-        // Send HANDSHAKE_INIT
+        // Send HANDSHAKE_INIT, encrypted to the inviter
         let init = HandshakeInit {
             sender_id52: self.public_key.to_bytes(),
             preimage_for_peer: my_preimage,
             relay_url: relay_addr.to_string(),
         };
+        let payload = crypto::seal(&their_id52, &init.to_bytes()?)?;
 
-        let result = conn.send(their_id52, their_preimage, init.to_bytes()).await?;
+        let result = conn.send(their_id52, their_preimage, payload).await?;
 
-        // Check for HANDSHAKE_COMPLETE
+        // Check for HANDSHAKE_COMPLETE (a plaintext response is refused)
         if result.status == crate::SEND_OK {
-            let complete = HandshakeComplete::from_bytes(&result.payload)?;
+            let plaintext = crypto::open(&self.secret_key.to_bytes(), &result.payload)
+                .map_err(|e| format!("handshake response not accepted: {}", e))?;
+            let complete = HandshakeComplete::from_bytes(&plaintext)?;
 
             if complete.status == HANDSHAKE_ACCEPTED {
                 let relay = if complete.relay_url.is_empty() {
@@ -194,8 +226,10 @@ impl<S: Send + Sync + 'static> Node<S> {
                 );
                 self.save();
 
+                info!("Paired with {} as {:?}", Id52(&their_id52), alias);
                 Ok(())
             } else {
+                warn!("Pairing with {} rejected", Id52(&their_id52));
                 Err("handshake rejected".into())
             }
         } else {
@@ -210,6 +244,21 @@ impl<S: Send + Sync + 'static> Node<S> {
         peer_alias: &str,
         cmd: &str,
         args: JsonValue,
+    ) -> Result<JsonValue, Box<dyn std::error::Error>> {
+        self.send_with_timeout(relay_addr, peer_alias, cmd, args, None).await
+    }
+
+    /// Send a command to a paired peer, giving up if it hasn't answered
+    /// within `timeout` (the relay's default when `None`)
+    ///
+    /// The relay keeps the timeout within its configured range.
+    pub async fn send_with_timeout(
+        &mut self,
+        relay_addr: &str,
+        peer_alias: &str,
+        cmd: &str,
+        args: JsonValue,
+        timeout: Option<Duration>,
     ) -> Result<JsonValue, Box<dyn std::error::Error>> {
         // Find the peer
         let (peer_id52, _) = self.state.find_peer_by_alias(peer_alias)
@@ -220,14 +269,17 @@ impl<S: Send + Sync + 'static> Node<S> {
             .ok_or("no preimage for peer")?;
 
         // Connect anonymously (sender identity not revealed to relay)
-        let mut conn = Connection::connect_anonymous(relay_addr).await?;
+        let conn = Connection::connect_anonymous(relay_addr).await?;
 
-        // Create request
+        // Create request, encrypted to the peer
         let request = Request::with_args(cmd, args);
-        let payload = serde_json::to_vec(&request)?;
+        let payload = crypto::seal(&peer_id52, &serde_json::to_vec(&request)?)?;
 
         // Send command
-        let result = conn.send(peer_id52, preimage, payload).await?;
+        let result = match timeout {
+            Some(timeout) => conn.send_with_deadline(peer_id52, preimage, payload, timeout).await?,
+            None => conn.send(peer_id52, preimage, payload).await?,
+        };
 
         if result.status != crate::SEND_OK {
             let status_msg = match result.status {
@@ -235,24 +287,35 @@ impl<S: Send + Sync + 'static> Node<S> {
                 crate::SEND_ERR_INVALID_PREIMAGE => "invalid preimage (recipient doesn't recognize it - may need to re-pair)",
                 crate::SEND_ERR_TIMEOUT => "recipient timed out",
                 crate::SEND_ERR_DISCONNECTED => "recipient disconnected during request",
+                crate::SEND_ERR_NOT_ENCRYPTED => "recipient requires an encrypted payload",
+                crate::SEND_ERR_RATE_LIMITED => "relay is rate limiting our sends",
+                crate::SEND_ERR_TOO_MANY_IN_FLIGHT => "recipient has too many requests in flight",
+                crate::SEND_ERR_BUSY => "recipient is busy",
+                crate::SEND_ERR_REFUSED => "peer rejected the request (unknown preimage or undecryptable payload)",
                 _ => "unknown error",
             };
             return Err(format!("send failed: {} (status {})", status_msg, result.status).into());
         }
 
+        // Relays without NACK pass a refusal on as an empty response
+        if result.payload.is_empty() {
+            return Err("peer rejected the request (unknown preimage or undecryptable payload)".into());
+        }
+        let plaintext = crypto::open(&self.secret_key.to_bytes(), &result.payload)?;
+
         // Parse response (may have new preimage appended)
-        let response_len = result.payload.len();
+        let response_len = plaintext.len();
         let (response_bytes, new_preimage) = if response_len > 32 {
-            match serde_json::from_slice::<Response>(&result.payload) {
-                Ok(_) => (result.payload.as_slice(), None),
+            match serde_json::from_slice::<Response>(&plaintext) {
+                Ok(_) => (plaintext.as_slice(), None),
                 Err(_) => {
                     let split = response_len - 32;
-                    let new_pre: [u8; 32] = result.payload[split..].try_into().unwrap();
-                    (&result.payload[..split], Some(new_pre))
+                    let new_pre: [u8; 32] = plaintext[split..].try_into().unwrap();
+                    (&plaintext[..split], Some(new_pre))
                 }
             }
         } else {
-            (result.payload.as_slice(), None)
+            (plaintext.as_slice(), None)
         };
 
         let response: Response = serde_json::from_slice(response_bytes)?;
@@ -270,28 +333,79 @@ impl<S: Send + Sync + 'static> Node<S> {
         }
     }
 
+    /// Ask a relay where a paired peer was last seen
+    ///
+    /// Returns the peer's own signed PRESENCE after checking its signature and
+    /// TTL, or `None` if the relay has no fresh one.
+    pub async fn locate(
+        &self,
+        relay_addr: &str,
+        peer_alias: &str,
+    ) -> Result<Option<Presence>, Box<dyn std::error::Error>> {
+        let (peer_id52, _) = self.state.find_peer_by_alias(peer_alias)
+            .ok_or_else(|| format!("peer '{}' not found", peer_alias))?;
+
+        let conn = Connection::connect_anonymous(relay_addr).await?;
+        let Some(signed) = conn.query_presence(peer_id52).await? else {
+            return Ok(None);
+        };
+
+        if signed.presence.id52 != peer_id52 {
+            return Err("relay returned presence for a different id52".into());
+        }
+        signed.verify(unix_now())
+            .map_err(|e| format!("relay returned invalid presence: {}", e))?;
+
+        Ok(Some(signed.presence))
+    }
+
     // =========================================================================
     // Server-side: run and handle incoming messages
     // =========================================================================
 
     /// Run the node, connecting to relay and handling incoming messages
+    #[tracing::instrument(name = "node", skip_all, fields(id52 = %Id52(&self.public_key.to_bytes())))]
     pub async fn run(&mut self, relay_addr: &str) -> Result<(), Box<dyn std::error::Error>> {
         self.relay_addr = Some(relay_addr.to_string());
         let mut conn = Connection::connect(relay_addr, &self.secret_key, self.get_commits()).await?;
+        conn.set_keepalive(self.keepalive);
+        if let Some(ttl) = self.presence_ttl {
+            conn.publish_presence(&self.secret_key, ttl).await?;
+        }
+        self.flush_revoked(&conn).await?;
+        info!("Connected to relay {}", relay_addr);
 
         loop {
-            let msg = match conn.receive().await {
-                Ok(m) => m,
+            let deliver = match conn.receive_deliver().await {
+                Ok(d) => d,
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    info!("Relay closed the connection");
                     break;
                 }
                 Err(e) => return Err(e.into()),
             };
 
+            // Plaintext or undecryptable payloads are refused with a NACK: we
+            // can't tell who sent them, so there is no one to encrypt a reply to
+            let msg_id = deliver.msg_id;
+            let span = info_span!("msg", msg_id);
+            let msg = match IncomingMessage::open(deliver, &self.secret_key) {
+                Ok(m) => m,
+                Err(e) => {
+                    span.in_scope(|| debug!("Refused undecryptable DELIVER: {}", e));
+                    conn.send_nack(msg_id, NACK_MALFORMED).await?;
+                    continue;
+                }
+            };
+
             if msg.msg_type == Some(DEV_HANDSHAKE_INIT) {
-                self.handle_handshake(&mut conn, msg.msg_id, &msg.preimage, &msg.payload).await?;
+                self.handle_handshake(&conn, msg.msg_id, &msg.preimage, &msg.payload)
+                    .instrument(span)
+                    .await?;
             } else {
-                self.handle_command(&mut conn, msg.msg_id, &msg.preimage, &msg.payload).await?;
+                self.handle_command(&conn, msg.msg_id, &msg.preimage, &msg.payload)
+                    .instrument(span)
+                    .await?;
             }
         }
 
@@ -300,12 +414,22 @@ impl<S: Send + Sync + 'static> Node<S> {
 
     async fn handle_handshake(
         &mut self,
-        conn: &mut Connection,
+        conn: &Connection,
         msg_id: u32,
         preimage: &[u8; 32],
         payload: &[u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let init = HandshakeInit::from_bytes(payload)?;
+        // Refused like an undecryptable DELIVER: without a valid init there
+        // is no one to encrypt a HANDSHAKE_COMPLETE to
+        let init = match HandshakeInit::from_bytes(payload) {
+            Ok(init) => init,
+            Err(e) => {
+                debug!("Refused malformed HANDSHAKE_INIT: {}", e);
+                conn.send_nack(msg_id, NACK_MALFORMED).await?;
+                return Ok(());
+            }
+        };
+        let peer_id52 = init.sender_id52;
 
         if let Some((new_preimage, new_commit)) = self.state.complete_handshake_as_inviter(
             preimage,
@@ -322,15 +446,17 @@ impl<S: Send + Sync + 'static> Node<S> {
                 relay_url: relay_addr,
             };
 
-            conn.send_ack(msg_id, complete.to_bytes()).await?;
+            conn.send_ack(msg_id, crypto::seal(&peer_id52, &complete.to_bytes()?)?).await?;
             conn.update_commits(vec![new_commit]).await?;
+            info!("Paired with {} (accepted invite)", Id52(&peer_id52));
         } else {
+            warn!("Handshake from {} with an unknown invite, rejected", Id52(&peer_id52));
             let complete = HandshakeComplete {
                 status: HANDSHAKE_REJECTED,
                 preimage_for_peer: [0u8; 32],
                 relay_url: String::new(),
             };
-            conn.send_ack(msg_id, complete.to_bytes()).await?;
+            conn.send_ack(msg_id, crypto::seal(&peer_id52, &complete.to_bytes()?)?).await?;
         }
 
         Ok(())
@@ -338,7 +464,7 @@ impl<S: Send + Sync + 'static> Node<S> {
 
     async fn handle_command(
         &mut self,
-        conn: &mut Connection,
+        conn: &Connection,
         msg_id: u32,
         preimage: &[u8; 32],
         payload: &[u8],
@@ -347,8 +473,9 @@ impl<S: Send + Sync + 'static> Node<S> {
         let (peer_id52, peer_alias, role) = match self.state.lookup_preimage(preimage) {
             Some(PreimageLookup::Peer(id52, peer)) => (id52, peer.alias.clone(), peer.role),
             _ => {
-                let response = Response::err("unauthorized");
-                conn.send_ack(msg_id, serde_json::to_vec(&response)?).await?;
+                // Unknown sender: nobody to encrypt a response to
+                debug!("DELIVER with an unknown preimage, refused");
+                conn.send_nack(msg_id, NACK_UNKNOWN_PREIMAGE).await?;
                 return Ok(());
             }
         };
@@ -357,7 +484,10 @@ impl<S: Send + Sync + 'static> Node<S> {
 
         // Parse request
         let response = match serde_json::from_slice::<Request>(payload) {
-            Ok(req) => self.dispatch_command(&ctx, &req),
+            Ok(req) => {
+                debug!("Command {:?} from {}", req.cmd, Id52(&peer_id52));
+                self.dispatch_command(&ctx, &req)
+            }
             Err(e) => Response::err(format!("invalid request: {}", e)),
         };
 
@@ -371,7 +501,25 @@ impl<S: Send + Sync + 'static> Node<S> {
             conn.update_commits(vec![new_commit]).await?;
         }
 
-        conn.send_ack(msg_id, response_bytes).await?;
+        self.flush_revoked(conn).await?;
+
+        conn.send_ack(msg_id, crypto::seal(&peer_id52, &response_bytes)?).await?;
+        Ok(())
+    }
+
+    /// Withdraw the commits in `revoked` from the relay, if it supports
+    /// REVOKE_COMMITS
+    ///
+    /// Otherwise they stay pending for the next relay that does. Meanwhile
+    /// the relay keeps them until we reconnect, but the preimages are
+    /// unknown to us by then, so their DELIVERs are refused.
+    async fn flush_revoked(&mut self, conn: &Connection) -> std::io::Result<()> {
+        if self.revoked.is_empty() || !conn.relay_supports(CAP_COMMIT_EXPIRY) {
+            return Ok(());
+        }
+        debug!("Revoking {} commits", self.revoked.len());
+        conn.revoke_commits(self.revoked.clone()).await?;
+        self.revoked.clear();
         Ok(())
     }
 
@@ -431,8 +579,8 @@ impl<S: Send + Sync + 'static> Node<S> {
                 let found = self.state.invites.keys()
                     .find(|p| p[..prefix.len().min(32)] == prefix[..])
                     .cloned();
-                if let Some(preimage) = found {
-                    self.state.invites.remove(&preimage);
+                if let Some(commit) = found.and_then(|preimage| self.state.delete_invite(&preimage)) {
+                    self.revoked.push(commit);
                     self.save();
                     Response::ok(json!({ "deleted": true }))
                 } else {
@@ -459,6 +607,26 @@ impl<S: Send + Sync + 'static> Node<S> {
                 Response::ok(json!({ "peers": peers }))
             }
 
+            "peers/remove" => {
+                if ctx.role != PeerRole::Owner {
+                    return Response::err("permission denied: owner only");
+                }
+                let id = match req.args.get("id").and_then(|v| v.as_str()) {
+                    Some(id) => id,
+                    None => return Response::err("missing id"),
+                };
+                let found = self.state.peers.keys()
+                    .find(|id52| data_encoding::BASE32_DNSSEC.encode(&id52[..10]) == id)
+                    .cloned();
+                if let Some(commits) = found.and_then(|id52| self.state.remove_peer(&id52)) {
+                    self.revoked.extend(commits);
+                    self.save();
+                    Response::ok(json!({ "removed": true }))
+                } else {
+                    Response::err("peer not found")
+                }
+            }
+
             // Custom command
             cmd => {
                 if let Some(handler) = self.handlers.get(cmd) {
@@ -474,3 +642,48 @@ impl<S: Send + Sync + 'static> Node<S> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bhumi_proto::{BhumiCodec, Deliver, Hello, Nack, RelayMessage, CAP_NACK};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    /// A HANDSHAKE_INIT that doesn't parse is NACKed, and the node goes on
+    /// to handle the next DELIVER
+    #[tokio::test]
+    async fn malformed_handshake_is_nacked() {
+        let home = std::env::temp_dir().join(format!("bhumi-node-test-{}", std::process::id()));
+        let mut node = Node::new(home.clone(), NodeConfig::default());
+        let public_key = node.public_key;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            let mut reader = FramedRead::new(reader, BhumiCodec::new());
+            let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+            writer.send(RelayMessage::from(Hello::new(1, 64 * 1024, CAP_NACK))).await.unwrap();
+            let mut next = async || reader.next().await.unwrap().unwrap().decode().unwrap();
+            assert!(matches!(next().await, RelayMessage::IAm(_)));
+
+            let garbage = crypto::seal(&public_key.to_bytes(), &[DEV_HANDSHAKE_INIT, 0xff, 0xff]).unwrap();
+            for msg_id in [1, 2] {
+                writer.send(RelayMessage::from(Deliver { msg_id, preimage: [1; 32], payload: garbage.clone() })).await.unwrap();
+                match next().await {
+                    RelayMessage::Nack(nack) => assert_eq!(nack, Nack { msg_id, reason: NACK_MALFORMED }),
+                    other => panic!("expected NACK, got {:?}", other),
+                }
+            }
+        });
+
+        let run = tokio::spawn(async move { node.run(&addr).await.map_err(|e| e.to_string()) });
+        relay.await.unwrap();
+        // Closing the connection ends the run loop cleanly
+        assert!(run.await.unwrap().is_ok());
+        let _ = std::fs::remove_dir_all(home);
+    }
+}
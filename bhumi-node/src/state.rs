@@ -19,7 +19,7 @@ fn hex_to_bytes(hex: &str) -> Option<[u8; 32]> {
 }
 
 /// Role of a peer - determines what commands they can execute
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PeerRole {
     /// Owner: full control, can manage invites
@@ -27,15 +27,10 @@ pub enum PeerRole {
     /// Writer: can read and modify device state
     Writer,
     /// Reader: can only read device state
+    #[default]
     Reader,
 }
 
-impl Default for PeerRole {
-    fn default() -> Self {
-        Self::Reader
-    }
-}
-
 /// Pending invite I created, awaiting handshake
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InviteRecord {
@@ -304,6 +299,18 @@ impl DeviceState {
         true
     }
 
+    /// Delete an invite; returns its commit, for withdrawing from the relay
+    pub fn delete_invite(&mut self, preimage: &[u8; 32]) -> Option<[u8; 32]> {
+        let invite = self.invites.remove(preimage)?;
+        Some(sha256(&invite.preimage))
+    }
+
+    /// Forget a peer; returns the commits of the preimages issued to it
+    pub fn remove_peer(&mut self, peer_id52: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
+        let peer = self.peers.remove(peer_id52)?;
+        Some(peer.issued_preimages.iter().map(|preimage| sha256(preimage)).collect())
+    }
+
     /// Get all commits to register with relay
     pub fn get_all_commits(&self) -> Vec<[u8; 32]> {
         let mut commits = Vec::new();
@@ -0,0 +1,91 @@
+//! Relay addresses, as devices and peer relays name them
+//!
+//! ```text
+//! tls://relay.example.com:443?id52=<relay id52>    certificate key is the relay's Ed25519 identity
+//! tls://relay.example.com:443?sha256=<hex>         SHA-256 of the relay's certificate (DER)
+//! tls://relay.example.com:443?ca=/etc/bhumi/ca.pem CA bundle, normal chain + hostname checks
+//! ```
+//!
+//! Anything else is plain TCP (`host:port`), for local development.
+//!
+//! Any address can also name the relay's identity: `host:port?id52=<id52>`
+//! (or a `tls://...?id52=` pin) asks whoever speaks the protocol over the
+//! connection to check the relay's signed HELLO against it.
+
+use std::path::PathBuf;
+
+use fastn_id52::PublicKey;
+
+/// How a TLS relay proves who it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayPin {
+    /// Certificate's public key must be this Ed25519 relay identity
+    Id52(PublicKey),
+    /// SHA-256 of the end-entity certificate (DER)
+    CertSha256([u8; 32]),
+    /// PEM file of CA certificates to validate the chain and hostname against
+    Ca(PathBuf),
+}
+
+/// Parsed relay address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayAddr {
+    /// `host:port` to connect to
+    pub addr: String,
+    /// Host name for SNI and CA validation
+    pub host: String,
+    /// Pin if this is a `tls://` address
+    pub tls: Option<RelayPin>,
+    /// Relay identity its HELLO must be signed by
+    pub relay_id52: Option<PublicKey>,
+}
+
+impl RelayAddr {
+    pub fn parse(s: &str) -> std::io::Result<Self> {
+        let Some(rest) = s.strip_prefix("tls://") else {
+            let rest = s.strip_prefix("tcp://").unwrap_or(s);
+            let (addr, relay_id52) = match rest.split_once('?') {
+                Some((addr, query)) => match query.split_once('=') {
+                    Some(("id52", id52)) => (addr, Some(parse_id52(s, id52)?)),
+                    _ => return Err(invalid(format!("{s}: unknown parameter {query}"))),
+                },
+                None => (rest, None),
+            };
+            return Ok(Self { addr: addr.to_string(), host: host_of(addr).to_string(), tls: None, relay_id52 });
+        };
+
+        let (addr, query) = rest.split_once('?')
+            .ok_or_else(|| invalid(format!("{s}: tls:// address needs ?id52=, ?sha256= or ?ca=")))?;
+        let pin = match query.split_once('=') {
+            Some(("id52", id52)) => RelayPin::Id52(parse_id52(s, id52)?),
+            Some(("sha256", hex)) => RelayPin::CertSha256(
+                data_encoding::HEXLOWER_PERMISSIVE.decode(hex.as_bytes()).ok()
+                    .and_then(|b| b.try_into().ok())
+                    .ok_or_else(|| invalid(format!("{s}: sha256 pin must be 64 hex digits")))?,
+            ),
+            Some(("ca", path)) => RelayPin::Ca(PathBuf::from(path)),
+            _ => return Err(invalid(format!("{s}: unknown pin {query}"))),
+        };
+
+        // The certificate key is the relay identity, so HELLO must match it too
+        let relay_id52 = match &pin {
+            RelayPin::Id52(id52) => Some(*id52),
+            _ => None,
+        };
+        Ok(Self { addr: addr.to_string(), host: host_of(addr).to_string(), tls: Some(pin), relay_id52 })
+    }
+}
+
+fn parse_id52(s: &str, id52: &str) -> std::io::Result<PublicKey> {
+    id52.parse().map_err(|e| invalid(format!("{s}: bad id52: {e}")))
+}
+
+/// `host` of `host:port` (brackets stripped from IPv6 literals)
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+pub(crate) fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,125 @@
+//! tokio-util codec for relay frames
+//!
+//! Use with `Framed`/`FramedRead`/`FramedWrite`. Both directions enforce the
+//! same payload limit, which starts at `MAX_FRAME_SIZE` and should be lowered
+//! to `Hello.max_payload_size` once it is known.
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Frame, MAX_FRAME_SIZE, ProtoError, RelayMessage};
+
+/// Frame codec with a negotiated maximum payload size
+#[derive(Debug, Clone)]
+pub struct BhumiCodec {
+    max_payload: usize,
+}
+
+impl Default for BhumiCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BhumiCodec {
+    /// Codec accepting payloads up to `MAX_FRAME_SIZE`
+    pub fn new() -> Self {
+        Self::with_max_payload(MAX_FRAME_SIZE)
+    }
+
+    /// Codec rejecting frames (in or out) whose payload exceeds `max_payload`
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        Self { max_payload }
+    }
+
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = max_payload;
+    }
+}
+
+impl Decoder for BhumiCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        let Some(header) = src.first_chunk::<6>() else {
+            return Ok(None);
+        };
+        let (msg_type, len) = Frame::parse_header(header, self.max_payload)?;
+        if src.len() < 6 + len {
+            src.reserve(6 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(6);
+        let payload = src.split_to(len).to_vec();
+        Ok(Some(Frame::new(msg_type, payload)))
+    }
+}
+
+impl Encoder<Frame> for BhumiCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        if frame.payload.len() > self.max_payload {
+            return Err(ProtoError::Oversized {
+                what: "frame",
+                len: frame.payload.len(),
+                max: self.max_payload,
+            }
+            .into());
+        }
+        dst.reserve(6 + frame.payload.len());
+        dst.put_slice(&frame.header()?);
+        dst.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+impl Encoder<RelayMessage> for BhumiCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: RelayMessage, dst: &mut BytesMut) -> io::Result<()> {
+        self.encode(msg.encode()?, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Ack, MSG_ACK};
+
+    #[test]
+    fn roundtrip_and_partial_input() {
+        let mut codec = BhumiCodec::new();
+        let mut buf = BytesMut::new();
+        let ack = RelayMessage::Ack(Ack { msg_id: 3, payload: b"ok".to_vec() });
+        codec.encode(ack.clone(), &mut buf).unwrap();
+
+        let mut partial = buf.split_to(5);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        let frame = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(frame.msg_type, MSG_ACK);
+        assert_eq!(frame.decode().unwrap(), ack);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn enforces_limit_both_ways() {
+        let mut codec = BhumiCodec::with_max_payload(8);
+        let mut buf = BytesMut::new();
+        let err = codec.encode(Frame::new(MSG_ACK, vec![0; 9]), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(buf.is_empty());
+
+        BhumiCodec::new().encode(Frame::new(MSG_ACK, vec![0; 9]), &mut buf).unwrap();
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
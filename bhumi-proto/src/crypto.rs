@@ -0,0 +1,167 @@
+//! End-to-end payload encryption (device protocol §6)
+//!
+//! Payloads are sealed to the recipient's id52. The Ed25519 id52 is mapped to
+//! its X25519 (Montgomery) form, a fresh ephemeral key is used per message, and
+//! the shared secret is expanded with HKDF-SHA256 into an XChaCha20-Poly1305 key.
+//!
+//! ```text
+//! ENCRYPTED_PAYLOAD {
+//!     bytes[32] ephemeral_pubkey
+//!     bytes[24] nonce
+//!     bytes[16] tag
+//!     bytes[*]  ciphertext
+//! }
+//! ```
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey, StaticSecret};
+
+const HKDF_SALT: &[u8] = b"bhumi-v1";
+const HKDF_INFO: &[u8] = b"encrypt";
+
+pub const EPHEMERAL_KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+
+/// Bytes added to a plaintext by [`seal`]
+pub const OVERHEAD: usize = EPHEMERAL_KEY_LEN + NONCE_LEN + TAG_LEN;
+
+/// Errors from sealing or opening an encrypted payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// Recipient id52 is not a valid Ed25519 public key
+    InvalidRecipient,
+    /// Payload is shorter than the encryption header
+    Truncated,
+    /// Key agreement produced a low-order (all zero) shared secret
+    WeakKey,
+    /// Authentication failed: wrong recipient, tampered or plaintext payload
+    Decrypt,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidRecipient => write!(f, "invalid recipient id52"),
+            CryptoError::Truncated => write!(f, "encrypted payload truncated"),
+            CryptoError::WeakKey => write!(f, "weak ephemeral key"),
+            CryptoError::Decrypt => write!(f, "payload decryption failed"),
+        }
+    }
+}
+
+impl core::error::Error for CryptoError {}
+
+/// Encrypt `plaintext` so only the owner of `recipient_id52` can read it
+#[cfg(feature = "std")]
+pub fn seal(recipient_id52: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    seal_with_rng(&mut rand_core::OsRng, recipient_id52, plaintext)
+}
+
+/// [`seal`] with a caller-provided RNG, for targets without OS randomness
+pub fn seal_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    recipient_id52: &[u8; 32],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let recipient = ed25519_dalek::VerifyingKey::from_bytes(recipient_id52)
+        .map_err(|_| CryptoError::InvalidRecipient)?;
+    let recipient = X25519PublicKey::from(recipient.to_montgomery().to_bytes());
+
+    let ephemeral_secret = EphemeralSecret::random_from_rng(&mut *rng);
+    let ephemeral_public = X25519PublicKey::from(&ephemeral_secret);
+    let shared = ephemeral_secret.diffie_hellman(&recipient);
+    if !shared.was_contributory() {
+        return Err(CryptoError::WeakKey);
+    }
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+
+    let cipher = cipher(shared.as_bytes());
+    let mut ciphertext = plaintext.to_vec();
+    let tag = cipher
+        .encrypt_in_place_detached(XNonce::from_slice(&nonce), &[], &mut ciphertext)
+        .expect("plaintext within XChaCha20-Poly1305 length limit");
+
+    let mut buf = Vec::with_capacity(OVERHEAD + ciphertext.len());
+    buf.extend_from_slice(ephemeral_public.as_bytes());
+    buf.extend_from_slice(&nonce);
+    buf.extend_from_slice(&tag);
+    buf.extend_from_slice(&ciphertext);
+    Ok(buf)
+}
+
+/// Decrypt a payload sealed to us; `secret_key` is our Ed25519 secret key bytes
+pub fn open(secret_key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < OVERHEAD {
+        return Err(CryptoError::Truncated);
+    }
+
+    let ephemeral_public: [u8; 32] = sealed[0..32].try_into().unwrap();
+    let nonce = &sealed[32..56];
+    let tag = &sealed[56..72];
+
+    let signing_key = ed25519_dalek::SigningKey::from_bytes(secret_key);
+    let our_secret = StaticSecret::from(signing_key.to_scalar_bytes());
+    let shared = our_secret.diffie_hellman(&X25519PublicKey::from(ephemeral_public));
+    if !shared.was_contributory() {
+        return Err(CryptoError::WeakKey);
+    }
+
+    let cipher = cipher(shared.as_bytes());
+    let mut plaintext = sealed[OVERHEAD..].to_vec();
+    cipher
+        .decrypt_in_place_detached(XNonce::from_slice(nonce), &[], &mut plaintext, Tag::from_slice(tag))
+        .map_err(|_| CryptoError::Decrypt)?;
+
+    Ok(plaintext)
+}
+
+fn cipher(shared_secret: &[u8; 32]) -> XChaCha20Poly1305 {
+    let hkdf = Hkdf::<Sha256>::new(Some(HKDF_SALT), shared_secret);
+    let mut key = [0u8; 32];
+    hkdf.expand(HKDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    XChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    fn keypair() -> ([u8; 32], [u8; 32]) {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&secret);
+        (signing_key.to_bytes(), signing_key.verifying_key().to_bytes())
+    }
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let (secret, id52) = keypair();
+        let sealed = seal(&id52, b"toggle").unwrap();
+        assert_eq!(sealed.len(), OVERHEAD + 6);
+        assert_eq!(open(&secret, &sealed).unwrap(), b"toggle");
+    }
+
+    #[test]
+    fn open_rejects_wrong_key_and_plaintext() {
+        let (_, id52) = keypair();
+        let (other_secret, _) = keypair();
+        let sealed = seal(&id52, b"toggle").unwrap();
+        assert_eq!(open(&other_secret, &sealed), Err(CryptoError::Decrypt));
+
+        let plaintext = br#"{"cmd":"status","args":null,"padding":"................................"}"#;
+        assert_eq!(open(&other_secret, plaintext), Err(CryptoError::Decrypt));
+        assert_eq!(open(&other_secret, b"{}"), Err(CryptoError::Truncated));
+    }
+}
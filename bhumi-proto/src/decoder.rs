@@ -0,0 +1,108 @@
+//! Sans-IO frame decoder
+//!
+//! Feed it whatever bytes the transport produced (partial frames, several
+//! frames at once) and pull out complete frames. Works without `std`.
+
+use alloc::vec::Vec;
+
+use crate::{Frame, MAX_FRAME_SIZE, ProtoError};
+
+/// Incremental decoder for the relay framing (`u16 type, u32 len, payload`)
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    max_payload: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    /// Decoder accepting payloads up to `MAX_FRAME_SIZE`
+    pub fn new() -> Self {
+        Self::with_max_payload(MAX_FRAME_SIZE)
+    }
+
+    /// Decoder rejecting frames whose payload exceeds `max_payload` bytes
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        Self { buf: Vec::new(), max_payload }
+    }
+
+    /// Change the payload limit (e.g. after learning the peer's limit)
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = max_payload;
+    }
+
+    /// Append received bytes
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Bytes received but not yet returned as a frame
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Next complete frame, `Ok(None)` if more bytes are needed
+    ///
+    /// An oversized header is reported as soon as it arrives, before its
+    /// payload is buffered. The stream can't be resynchronised after an
+    /// error, so the caller should drop the connection.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, ProtoError> {
+        let Some(header) = self.buf.first_chunk::<6>() else {
+            return Ok(None);
+        };
+        let (msg_type, len) = Frame::parse_header(header, self.max_payload)?;
+        if self.buf.len() < 6 + len {
+            return Ok(None);
+        }
+
+        let payload = self.buf[6..6 + len].to_vec();
+        self.buf.drain(..6 + len);
+        Ok(Some(Frame::new(msg_type, payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RelayMessage;
+
+    #[test]
+    fn decodes_split_and_coalesced_frames() {
+        let mut bytes = RelayMessage::Keepalive.encode().unwrap().to_bytes().unwrap();
+        let ack = Frame::new(crate::MSG_ACK, alloc::vec![0, 0, 0, 1, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&ack.to_bytes().unwrap());
+
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        // One byte at a time
+        for b in &bytes {
+            decoder.feed(core::slice::from_ref(b));
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, [RelayMessage::Keepalive.encode().unwrap(), ack.clone()]);
+        assert_eq!(decoder.buffered(), 0);
+
+        // All at once
+        decoder.feed(&bytes);
+        assert_eq!(decoder.next_frame().unwrap().unwrap().msg_type, crate::MSG_KEEPALIVE);
+        assert_eq!(decoder.next_frame().unwrap(), Some(ack));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        let mut decoder = FrameDecoder::with_max_payload(16);
+        decoder.feed(&[0, 3, 0, 0, 0, 17]);
+        assert_eq!(
+            decoder.next_frame(),
+            Err(ProtoError::Oversized { what: "frame", len: 17, max: 16 })
+        );
+    }
+}
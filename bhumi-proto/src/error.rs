@@ -0,0 +1,43 @@
+//! Protocol decode/encode errors
+
+use core::fmt;
+
+/// Error decoding or encoding a protocol message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtoError {
+    /// Message ended before all of its fields could be read
+    Truncated { msg: &'static str },
+    /// A frame or field is longer than its limit or its length prefix allows
+    Oversized { what: &'static str, len: usize, max: usize },
+    /// Relay frame with a message type we don't know
+    UnknownType(u16),
+    /// Device message whose type byte doesn't match what was expected
+    UnexpectedType { expected: u8, got: u8 },
+    /// String field is not valid UTF-8
+    InvalidUtf8 { field: &'static str },
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoError::Truncated { msg } => write!(f, "{} truncated", msg),
+            ProtoError::Oversized { what, len, max } => {
+                write!(f, "{} too large ({} > {})", what, len, max)
+            }
+            ProtoError::UnknownType(t) => write!(f, "unknown message type 0x{:04x}", t),
+            ProtoError::UnexpectedType { expected, got } => {
+                write!(f, "expected device message 0x{:02x}, got 0x{:02x}", expected, got)
+            }
+            ProtoError::InvalidUtf8 { field } => write!(f, "invalid utf8 in {}", field),
+        }
+    }
+}
+
+impl core::error::Error for ProtoError {}
+
+#[cfg(feature = "std")]
+impl From<ProtoError> for std::io::Error {
+    fn from(e: ProtoError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
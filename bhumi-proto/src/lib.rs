@@ -1,8 +1,45 @@
 //! Bhumi wire protocol - message types and framing
+//!
+//! Builds as `no_std` + `alloc` with `default-features = false`; feed received
+//! bytes to a [`FrameDecoder`] and send [`Frame::to_bytes`]. The `std` feature
+//! adds blocking `Read`/`Write` adapters, relay addresses and id52 log
+//! redaction, `async` adds a tokio-util codec, `tokio` the `async_io` helpers
+//! built on it and `tls` connecting to relay addresses.
 
-pub mod ble;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
+#[cfg(feature = "std")]
+pub mod addr;
+pub mod ble;
+#[cfg(feature = "async")]
+pub mod codec;
+pub mod crypto;
+mod decoder;
+mod error;
+pub mod presence;
+#[cfg(feature = "std")]
+pub mod redact;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod wire;
+
+#[cfg(feature = "std")]
+pub use addr::{RelayAddr, RelayPin};
+#[cfg(feature = "async")]
+pub use codec::BhumiCodec;
+pub use decoder::FrameDecoder;
+pub use error::ProtoError;
+pub use presence::{Presence, PresenceError, PresenceQuery, PresenceResult, SignedPresence};
+#[cfg(feature = "tls")]
+pub use tls::Transport;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::io::{self, Read, Write};
+use wire::{Reader, len_u32, put_array32s_u16, put_bytes_u32, put_string_u16};
 
 // Message types
 pub const MSG_HELLO: u16 = 0x0001;
@@ -13,6 +50,12 @@ pub const MSG_ACK: u16 = 0x0005;
 pub const MSG_KEEPALIVE: u16 = 0x0006;
 pub const MSG_SEND_RESULT: u16 = 0x0007;
 pub const MSG_UPDATE_COMMITS: u16 = 0x0008;
+pub const MSG_PRESENCE: u16 = 0x0009;
+pub const MSG_PRESENCE_QUERY: u16 = 0x000A;
+pub const MSG_PRESENCE_RESULT: u16 = 0x000B;
+pub const MSG_RELAY_PEER: u16 = 0x000C;
+pub const MSG_REVOKE_COMMITS: u16 = 0x000D;
+pub const MSG_NACK: u16 = 0x000E;
 
 // SEND_RESULT status codes
 pub const SEND_OK: u8 = 0;
@@ -20,6 +63,41 @@ pub const SEND_ERR_NOT_CONNECTED: u8 = 1;
 pub const SEND_ERR_INVALID_PREIMAGE: u8 = 2;
 pub const SEND_ERR_TIMEOUT: u8 = 3;
 pub const SEND_ERR_DISCONNECTED: u8 = 4;
+pub const SEND_ERR_NOT_ENCRYPTED: u8 = 5;
+pub const SEND_ERR_RATE_LIMITED: u8 = 6;
+pub const SEND_ERR_TOO_MANY_IN_FLIGHT: u8 = 7;
+pub const SEND_ERR_BUSY: u8 = 8;
+pub const SEND_ERR_REFUSED: u8 = 9;
+
+// NACK reason codes
+/// Payload could not be decrypted or parsed
+pub const NACK_MALFORMED: u8 = 1;
+/// Recipient can't take the message right now; the sender may retry
+pub const NACK_BUSY: u8 = 2;
+/// Preimage not (or no longer) known to the recipient
+pub const NACK_UNKNOWN_PREIMAGE: u8 = 3;
+
+/// Protocol version sent in HELLO and I_AM
+///
+/// Version 1 frames have no capability field; version 2 appends one. Older
+/// parsers ignore the extra bytes, and a missing field parses as no capabilities.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+// Capability bits (HELLO: what the relay supports, I_AM: what the client supports)
+/// KEEPALIVE is answered / sent; the relay only drops idle sessions that announce it
+pub const CAP_KEEPALIVE: u32 = 1 << 0;
+/// Payloads for this client must be sealed; the relay refuses obvious plaintext
+pub const CAP_ENCRYPTION_REQUIRED: u32 = 1 << 1;
+/// PRESENCE, PRESENCE_QUERY and PRESENCE_RESULT are understood
+pub const CAP_PRESENCE: u32 = 1 << 2;
+/// Relay accepts RELAY_PEER and gossips PRESENCE with other relays (HELLO only)
+pub const CAP_RELAY_PEERING: u32 = 1 << 3;
+/// Relay honours UPDATE_COMMITS TTLs and accepts REVOKE_COMMITS (HELLO only)
+pub const CAP_COMMIT_EXPIRY: u32 = 1 << 4;
+/// Relay answers a NACK'd DELIVER with SEND_ERR_BUSY or SEND_ERR_REFUSED (HELLO only)
+pub const CAP_NACK: u32 = 1 << 5;
+/// Relay echoes SEND request ids in SEND_RESULT, and may answer SENDs out of order (HELLO only)
+pub const CAP_REQUEST_IDS: u32 = 1 << 6;
 
 // Device protocol message types (inside encrypted payload)
 pub const DEV_HANDSHAKE_INIT: u8 = 0x01;
@@ -31,57 +109,117 @@ pub const DEV_MESSAGE_RESPONSE: u8 = 0x11;
 pub const HANDSHAKE_ACCEPTED: u8 = 0;
 pub const HANDSHAKE_REJECTED: u8 = 1;
 
+/// Default limit on frame payloads until `Hello.max_payload_size` is known
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
 /// HELLO message sent by relay on connection
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Hello {
     pub version: u8,
     pub nonce: u32,
     pub max_payload_size: u32,
+    pub capabilities: u32,  // CAP_* bits (0 from a version 1 relay)
+    pub identity: Option<RelayIdentity>,  // relays without a key send none
+}
+
+/// Relay id52 and its signature over the rest of HELLO
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayIdentity {
+    pub relay_id52: [u8; 32],
+    pub signature: [u8; 64],  // Sign(version || nonce || max_payload_size || capabilities || relay_id52)
 }
 
 impl Hello {
-    pub fn new(nonce: u32, max_payload_size: u32) -> Self {
+    pub fn new(nonce: u32, max_payload_size: u32, capabilities: u32) -> Self {
         Self {
-            version: 1,
+            version: PROTOCOL_VERSION,
             nonce,
             max_payload_size,
+            capabilities,
+            identity: None,
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(9);
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+
+    /// Fields covered by the relay's signature, without the identity trailer
+    fn unsigned_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(13 + 96);
         buf.push(self.version);
         buf.extend_from_slice(&self.nonce.to_be_bytes());
         buf.extend_from_slice(&self.max_payload_size.to_be_bytes());
+        buf.extend_from_slice(&self.capabilities.to_be_bytes());
         buf
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 9 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "HELLO too short"));
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.unsigned_bytes();
+        if let Some(identity) = &self.identity {
+            buf.extend_from_slice(&identity.relay_id52);
+            buf.extend_from_slice(&identity.signature);
         }
-        Ok(Self {
-            version: data[0],
-            nonce: u32::from_be_bytes([data[1], data[2], data[3], data[4]]),
-            max_payload_size: u32::from_be_bytes([data[5], data[6], data[7], data[8]]),
-        })
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "HELLO");
+        let version = r.u8()?;
+        let nonce = r.u32()?;
+        let max_payload_size = r.u32()?;
+        let capabilities = if r.remaining() == 0 { 0 } else { r.u32()? };
+        let identity = if r.remaining() == 0 {
+            None
+        } else {
+            Some(RelayIdentity { relay_id52: r.array()?, signature: r.array()? })
+        };
+        Ok(Self { version, nonce, max_payload_size, capabilities, identity })
+    }
+
+    /// Sign as the relay identified by `secret_key`
+    #[cfg(feature = "std")]
+    pub fn sign(mut self, secret_key: &fastn_id52::SecretKey) -> Self {
+        let relay_id52 = secret_key.public_key().to_bytes();
+        let mut msg = self.unsigned_bytes();
+        msg.extend_from_slice(&relay_id52);
+        let signature = secret_key.sign(&msg).to_bytes();
+        self.identity = Some(RelayIdentity { relay_id52, signature });
+        self
+    }
+
+    /// The relay's id52 if HELLO carries one with a valid signature
+    ///
+    /// The signature covers the relay's own nonce, so on its own it doesn't
+    /// stop a captured HELLO from being replayed; TLS with an id52 pin does.
+    #[cfg(feature = "std")]
+    pub fn verified_relay_id52(&self) -> Option<fastn_id52::PublicKey> {
+        let identity = self.identity.as_ref()?;
+        let public_key = fastn_id52::PublicKey::from_bytes(&identity.relay_id52).ok()?;
+        let signature = fastn_id52::Signature::from_bytes(&identity.signature).ok()?;
+        let mut msg = self.unsigned_bytes();
+        msg.extend_from_slice(&identity.relay_id52);
+        public_key.verify(&msg, &signature).ok()?;
+        Some(public_key)
     }
 }
 
 /// Recent response for relay cache portability
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RecentResponse {
     pub preimage: [u8; 32],
     pub response: Vec<u8>,
 }
 
 /// I_AM message sent by device to authenticate and register commits
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IAm {
     pub id52: [u8; 32],       // Ed25519 public key
     pub signature: [u8; 64],  // Sign(nonce || id52)
     pub commits: Vec<[u8; 32]>, // SHA256 hashes of preimages
     pub recent_responses: Vec<RecentResponse>, // For relay cache portability
+    pub version: u8,          // 1 if the client sent no version
+    pub capabilities: u32,    // CAP_* bits
 }
 
 impl IAm {
@@ -91,15 +229,23 @@ impl IAm {
             signature,
             commits,
             recent_responses: Vec::new(),
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let commit_count = self.commits.len() as u16;
-        let response_count = self.recent_responses.len() as u16;
+    pub fn with_capabilities(mut self, capabilities: u32) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
 
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
         // Calculate size
-        let mut size = 32 + 64 + 2 + self.commits.len() * 32 + 2;
+        let mut size = 32 + 64 + 2 + self.commits.len() * 32 + 2 + 5;
         for resp in &self.recent_responses {
             size += 32 + 4 + resp.response.len();
         }
@@ -107,143 +253,193 @@ impl IAm {
         let mut buf = Vec::with_capacity(size);
         buf.extend_from_slice(&self.id52);
         buf.extend_from_slice(&self.signature);
-        buf.extend_from_slice(&commit_count.to_be_bytes());
-        for commit in &self.commits {
-            buf.extend_from_slice(commit);
-        }
-        buf.extend_from_slice(&response_count.to_be_bytes());
+        put_array32s_u16(&mut buf, "I_AM commits", &self.commits)?;
+        buf.extend_from_slice(&wire::len_u16("I_AM responses", self.recent_responses.len())?.to_be_bytes());
         for resp in &self.recent_responses {
             buf.extend_from_slice(&resp.preimage);
-            buf.extend_from_slice(&(resp.response.len() as u32).to_be_bytes());
-            buf.extend_from_slice(&resp.response);
+            put_bytes_u32(&mut buf, "I_AM response", &resp.response)?;
         }
-        buf
+        buf.push(self.version);
+        buf.extend_from_slice(&self.capabilities.to_be_bytes());
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 32 + 64 + 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "I_AM too short"));
-        }
-
-        let id52: [u8; 32] = data[0..32].try_into().unwrap();
-        let signature: [u8; 64] = data[32..96].try_into().unwrap();
-        let commit_count = u16::from_be_bytes([data[96], data[97]]) as usize;
-
-        let commits_end = 98 + commit_count * 32;
-        if data.len() < commits_end + 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "I_AM commits truncated"));
-        }
-
-        let mut commits = Vec::with_capacity(commit_count);
-        for i in 0..commit_count {
-            let start = 98 + i * 32;
-            let commit: [u8; 32] = data[start..start + 32].try_into().unwrap();
-            commits.push(commit);
-        }
-
-        let response_count = u16::from_be_bytes([data[commits_end], data[commits_end + 1]]) as usize;
-        let mut recent_responses = Vec::with_capacity(response_count);
-        let mut pos = commits_end + 2;
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "I_AM");
+        let id52 = r.array()?;
+        let signature = r.array()?;
+        let commits = r.array32s_u16()?;
 
+        let response_count = r.u16()? as usize;
+        let mut recent_responses = Vec::with_capacity(response_count.min(r.remaining() / 36));
         for _ in 0..response_count {
-            if data.len() < pos + 36 {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "I_AM responses truncated"));
-            }
-            let preimage: [u8; 32] = data[pos..pos + 32].try_into().unwrap();
-            let resp_len = u32::from_be_bytes([data[pos + 32], data[pos + 33], data[pos + 34], data[pos + 35]]) as usize;
-            pos += 36;
-
-            if data.len() < pos + resp_len {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "I_AM response data truncated"));
-            }
-            let response = data[pos..pos + resp_len].to_vec();
-            pos += resp_len;
-
+            let preimage = r.array()?;
+            let response = r.bytes_u32()?;
             recent_responses.push(RecentResponse { preimage, response });
         }
 
-        Ok(Self { id52, signature, commits, recent_responses })
+        // Version 1 clients end here
+        let (version, capabilities) = if r.remaining() == 0 {
+            (1, 0)
+        } else {
+            (r.u8()?, r.u32()?)
+        };
+
+        Ok(Self { id52, signature, commits, recent_responses, version, capabilities })
     }
 }
 
 /// SEND message - deliver a message to a recipient
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Send {
     pub to_id52: [u8; 32],
     pub preimage: [u8; 32],
     pub payload: Vec<u8>,
+    pub request_id: u32,  // echoed in SEND_RESULT; 0 (or absent) for none
+    pub deadline_ms: u32, // how long to wait for the recipient; 0 (or absent) for the relay's default
 }
 
 impl Send {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let payload_len = self.payload.len() as u32;
-        let mut buf = Vec::with_capacity(32 + 32 + 4 + self.payload.len());
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(32 + 32 + 4 + self.payload.len() + 8);
         buf.extend_from_slice(&self.to_id52);
         buf.extend_from_slice(&self.preimage);
-        buf.extend_from_slice(&payload_len.to_be_bytes());
-        buf.extend_from_slice(&self.payload);
-        buf
+        put_bytes_u32(&mut buf, "SEND payload", &self.payload)?;
+        buf.extend_from_slice(&self.request_id.to_be_bytes());
+        buf.extend_from_slice(&self.deadline_ms.to_be_bytes());
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 32 + 32 + 4 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "SEND too short"));
-        }
-
-        let to_id52: [u8; 32] = data[0..32].try_into().unwrap();
-        let preimage: [u8; 32] = data[32..64].try_into().unwrap();
-        let payload_len = u32::from_be_bytes([data[64], data[65], data[66], data[67]]) as usize;
-
-        if data.len() < 68 + payload_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "SEND payload truncated"));
-        }
-
-        let payload = data[68..68 + payload_len].to_vec();
-        Ok(Self { to_id52, preimage, payload })
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "SEND");
+        let to_id52 = r.array()?;
+        let preimage = r.array()?;
+        let payload = r.bytes_u32()?;
+        let request_id = if r.remaining() == 0 { 0 } else { r.u32()? };
+        let deadline_ms = if r.remaining() == 0 { 0 } else { r.u32()? };
+        Ok(Self { to_id52, preimage, payload, request_id, deadline_ms })
     }
 }
 
 /// UPDATE_COMMITS - add commits to an existing connection
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UpdateCommits {
     pub commits: Vec<[u8; 32]>,
+    pub ttl_secs: Vec<u32>,  // empty, or per commit (0 = no expiry); missing entries are 0
 }
 
 impl UpdateCommits {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let commit_count = self.commits.len() as u16;
-        let mut buf = Vec::with_capacity(2 + self.commits.len() * 32);
-        buf.extend_from_slice(&commit_count.to_be_bytes());
-        for commit in &self.commits {
-            buf.extend_from_slice(commit);
-        }
-        buf
+    pub fn new(commits: Vec<[u8; 32]>) -> Self {
+        Self { commits, ttl_secs: Vec::new() }
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "UPDATE_COMMITS too short"));
-        }
+    /// Every commit expires `ttl_secs` after the relay receives it
+    pub fn with_ttl(mut self, ttl_secs: u32) -> Self {
+        self.ttl_secs = alloc::vec![ttl_secs; self.commits.len()];
+        self
+    }
 
-        let commit_count = u16::from_be_bytes([data[0], data[1]]) as usize;
+    /// TTL of the `i`th commit, if it has one
+    pub fn ttl(&self, i: usize) -> Option<u32> {
+        self.ttl_secs.get(i).copied().filter(|ttl| *ttl != 0)
+    }
 
-        if data.len() < 2 + commit_count * 32 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "UPDATE_COMMITS truncated"));
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(2 + self.commits.len() * 36);
+        put_array32s_u16(&mut buf, "UPDATE_COMMITS commits", &self.commits)?;
+        if !self.ttl_secs.is_empty() {
+            for i in 0..self.commits.len() {
+                buf.extend_from_slice(&self.ttl(i).unwrap_or(0).to_be_bytes());
+            }
         }
+        Ok(buf)
+    }
 
-        let mut commits = Vec::with_capacity(commit_count);
-        for i in 0..commit_count {
-            let start = 2 + i * 32;
-            let commit: [u8; 32] = data[start..start + 32].try_into().unwrap();
-            commits.push(commit);
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "UPDATE_COMMITS");
+        let commits = r.array32s_u16()?;
+        let mut ttl_secs = Vec::new();
+        if r.remaining() != 0 {
+            ttl_secs.reserve(commits.len());
+            for _ in 0..commits.len() {
+                ttl_secs.push(r.u32()?);
+            }
         }
+        Ok(Self { commits, ttl_secs })
+    }
+}
+
+/// REVOKE_COMMITS - withdraw commits before they are used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevokeCommits {
+    pub commits: Vec<[u8; 32]>,
+}
+
+impl RevokeCommits {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(2 + self.commits.len() * 32);
+        put_array32s_u16(&mut buf, "REVOKE_COMMITS commits", &self.commits)?;
+        Ok(buf)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "REVOKE_COMMITS");
+        Ok(Self { commits: r.array32s_u16()? })
+    }
+}
+
+/// RELAY_PEER - sent instead of I_AM by a relay opening a gossip link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayPeer {
+    pub relay_id52: [u8; 32],  // identity the connecting relay signs its HELLO with
+    pub signature: [u8; 64],  // Sign(MSG_RELAY_PEER || nonce || relay_id52), nonce from the accepting relay's HELLO
+}
+
+impl RelayPeer {
+    /// What the connecting relay signs: its id52 bound to this session's nonce
+    #[cfg(feature = "std")]
+    fn signed_bytes(nonce: u32, relay_id52: &[u8; 32]) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(2 + 4 + 32);
+        msg.extend_from_slice(&MSG_RELAY_PEER.to_be_bytes());
+        msg.extend_from_slice(&nonce.to_be_bytes());
+        msg.extend_from_slice(relay_id52);
+        msg
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32 + 64);
+        buf.extend_from_slice(&self.relay_id52);
+        buf.extend_from_slice(&self.signature);
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "RELAY_PEER");
+        Ok(Self { relay_id52: r.array()?, signature: r.array()? })
+    }
 
-        Ok(Self { commits })
+    /// RELAY_PEER for the relay identified by `secret_key`, answering a
+    /// HELLO with `nonce`
+    #[cfg(feature = "std")]
+    pub fn sign(nonce: u32, secret_key: &fastn_id52::SecretKey) -> Self {
+        let relay_id52 = secret_key.public_key().to_bytes();
+        let signature = secret_key.sign(&Self::signed_bytes(nonce, &relay_id52)).to_bytes();
+        Self { relay_id52, signature }
+    }
+
+    /// The connecting relay's id52 if the signature covers `nonce` (the one
+    /// this session's HELLO carried)
+    #[cfg(feature = "std")]
+    pub fn verified_relay_id52(&self, nonce: u32) -> Option<fastn_id52::PublicKey> {
+        let public_key = fastn_id52::PublicKey::from_bytes(&self.relay_id52).ok()?;
+        let signature = fastn_id52::Signature::from_bytes(&self.signature).ok()?;
+        public_key.verify(&Self::signed_bytes(nonce, &self.relay_id52), &signature).ok()?;
+        Some(public_key)
     }
 }
 
 /// DELIVER message - relay forwards message to recipient
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Deliver {
     pub msg_id: u32,
     pub preimage: [u8; 32],  // The preimage used to route this message
@@ -251,107 +447,202 @@ pub struct Deliver {
 }
 
 impl Deliver {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let payload_len = self.payload.len() as u32;
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
         let mut buf = Vec::with_capacity(4 + 32 + 4 + self.payload.len());
         buf.extend_from_slice(&self.msg_id.to_be_bytes());
         buf.extend_from_slice(&self.preimage);
-        buf.extend_from_slice(&payload_len.to_be_bytes());
-        buf.extend_from_slice(&self.payload);
-        buf
+        put_bytes_u32(&mut buf, "DELIVER payload", &self.payload)?;
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 4 + 32 + 4 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "DELIVER too short"));
-        }
-
-        let msg_id = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-        let preimage: [u8; 32] = data[4..36].try_into().unwrap();
-        let payload_len = u32::from_be_bytes([data[36], data[37], data[38], data[39]]) as usize;
-
-        if data.len() < 40 + payload_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "DELIVER payload truncated"));
-        }
-
-        let payload = data[40..40 + payload_len].to_vec();
-        Ok(Self { msg_id, preimage, payload })
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "DELIVER");
+        Ok(Self {
+            msg_id: r.u32()?,
+            preimage: r.array()?,
+            payload: r.bytes_u32()?,
+        })
     }
 }
 
 /// ACK message - recipient's response to DELIVER
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ack {
     pub msg_id: u32,
     pub payload: Vec<u8>,  // encrypted response for sender
 }
 
 impl Ack {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let payload_len = self.payload.len() as u32;
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
         let mut buf = Vec::with_capacity(4 + 4 + self.payload.len());
         buf.extend_from_slice(&self.msg_id.to_be_bytes());
-        buf.extend_from_slice(&payload_len.to_be_bytes());
-        buf.extend_from_slice(&self.payload);
-        buf
+        put_bytes_u32(&mut buf, "ACK payload", &self.payload)?;
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 8 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "ACK too short"));
-        }
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "ACK");
+        Ok(Self {
+            msg_id: r.u32()?,
+            payload: r.bytes_u32()?,
+        })
+    }
+}
 
-        let msg_id = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-        let payload_len = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+/// NACK message - recipient refuses a DELIVER instead of answering it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nack {
+    pub msg_id: u32,
+    pub reason: u8,  // NACK_*; unknown reasons are treated as refusals
+}
 
-        if data.len() < 8 + payload_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "ACK payload truncated"));
+impl Nack {
+    /// SEND_RESULT status the sender gets
+    pub fn send_status(&self) -> u8 {
+        match self.reason {
+            NACK_BUSY => SEND_ERR_BUSY,
+            _ => SEND_ERR_REFUSED,
         }
+    }
 
-        let payload = data[8..8 + payload_len].to_vec();
-        Ok(Self { msg_id, payload })
+    /// Whether the relay restores the consumed commit, so the sender can
+    /// retry with the same preimage
+    ///
+    /// Only a busy recipient hasn't acted on the message; after any other
+    /// NACK a retry would be refused again, so the commit stays used.
+    pub fn restores_commit(&self) -> bool {
+        self.reason == NACK_BUSY
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5);
+        buf.extend_from_slice(&self.msg_id.to_be_bytes());
+        buf.push(self.reason);
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "NACK");
+        Ok(Self { msg_id: r.u32()?, reason: r.u8()? })
     }
 }
 
 /// SEND_RESULT message - relay's response to SEND
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SendResult {
     pub status: u8,
     pub payload: Vec<u8>,  // encrypted response from recipient (empty on error)
+    pub request_id: u32,   // from the SEND this answers (0 from relays that predate it)
 }
 
 impl SendResult {
     pub fn success(payload: Vec<u8>) -> Self {
-        Self { status: SEND_OK, payload }
+        Self { status: SEND_OK, payload, request_id: 0 }
     }
 
     pub fn error(status: u8) -> Self {
-        Self { status, payload: Vec::new() }
+        Self { status, payload: Vec::new(), request_id: 0 }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let payload_len = self.payload.len() as u32;
-        let mut buf = Vec::with_capacity(1 + 4 + self.payload.len());
+    pub fn with_request_id(mut self, request_id: u32) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(1 + 4 + self.payload.len() + 4);
         buf.push(self.status);
-        buf.extend_from_slice(&payload_len.to_be_bytes());
-        buf.extend_from_slice(&self.payload);
-        buf
+        put_bytes_u32(&mut buf, "SEND_RESULT payload", &self.payload)?;
+        buf.extend_from_slice(&self.request_id.to_be_bytes());
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 5 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "SEND_RESULT too short"));
-        }
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "SEND_RESULT");
+        let status = r.u8()?;
+        let payload = r.bytes_u32()?;
+        let request_id = if r.remaining() == 0 { 0 } else { r.u32()? };
+        Ok(Self { status, payload, request_id })
+    }
+}
 
-        let status = data[0];
-        let payload_len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+/// Any relay protocol message, decoded from a `Frame`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayMessage {
+    Hello(Hello),
+    IAm(IAm),
+    Send(Send),
+    Deliver(Deliver),
+    Ack(Ack),
+    Keepalive,
+    SendResult(SendResult),
+    UpdateCommits(UpdateCommits),
+    Presence(SignedPresence),
+    PresenceQuery(PresenceQuery),
+    PresenceResult(PresenceResult),
+    RelayPeer(RelayPeer),
+    RevokeCommits(RevokeCommits),
+    Nack(Nack),
+}
 
-        if data.len() < 5 + payload_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "SEND_RESULT payload truncated"));
+impl RelayMessage {
+    /// Wire message type (`MSG_*`)
+    pub fn msg_type(&self) -> u16 {
+        match self {
+            RelayMessage::Hello(_) => MSG_HELLO,
+            RelayMessage::IAm(_) => MSG_I_AM,
+            RelayMessage::Send(_) => MSG_SEND,
+            RelayMessage::Deliver(_) => MSG_DELIVER,
+            RelayMessage::Ack(_) => MSG_ACK,
+            RelayMessage::Keepalive => MSG_KEEPALIVE,
+            RelayMessage::SendResult(_) => MSG_SEND_RESULT,
+            RelayMessage::UpdateCommits(_) => MSG_UPDATE_COMMITS,
+            RelayMessage::Presence(_) => MSG_PRESENCE,
+            RelayMessage::PresenceQuery(_) => MSG_PRESENCE_QUERY,
+            RelayMessage::PresenceResult(_) => MSG_PRESENCE_RESULT,
+            RelayMessage::RelayPeer(_) => MSG_RELAY_PEER,
+            RelayMessage::RevokeCommits(_) => MSG_REVOKE_COMMITS,
+            RelayMessage::Nack(_) => MSG_NACK,
         }
+    }
+
+    /// Encode into a frame, failing if a count or length doesn't fit its field
+    pub fn encode(&self) -> Result<Frame, ProtoError> {
+        let payload = match self {
+            RelayMessage::Hello(m) => m.to_bytes(),
+            RelayMessage::IAm(m) => m.to_bytes()?,
+            RelayMessage::Send(m) => m.to_bytes()?,
+            RelayMessage::Deliver(m) => m.to_bytes()?,
+            RelayMessage::Ack(m) => m.to_bytes()?,
+            RelayMessage::Keepalive => Vec::new(),
+            RelayMessage::SendResult(m) => m.to_bytes()?,
+            RelayMessage::UpdateCommits(m) => m.to_bytes()?,
+            RelayMessage::Presence(m) => m.to_bytes()?,
+            RelayMessage::PresenceQuery(m) => m.to_bytes(),
+            RelayMessage::PresenceResult(m) => m.to_bytes()?,
+            RelayMessage::RelayPeer(m) => m.to_bytes(),
+            RelayMessage::RevokeCommits(m) => m.to_bytes()?,
+            RelayMessage::Nack(m) => m.to_bytes(),
+        };
+        Ok(Frame::new(self.msg_type(), payload))
+    }
+}
+
+macro_rules! impl_from_message {
+    ($($variant:ident),*) => {
+        $(impl From<$variant> for RelayMessage {
+            fn from(m: $variant) -> Self {
+                RelayMessage::$variant(m)
+            }
+        })*
+    };
+}
+
+impl_from_message!(Hello, IAm, Send, Deliver, Ack, SendResult, UpdateCommits, PresenceQuery, PresenceResult, RelayPeer, RevokeCommits, Nack);
 
-        let payload = data[5..5 + payload_len].to_vec();
-        Ok(Self { status, payload })
+impl From<SignedPresence> for RelayMessage {
+    fn from(m: SignedPresence) -> Self {
+        RelayMessage::Presence(m)
     }
 }
 
@@ -359,8 +650,17 @@ impl SendResult {
 // Device Protocol Messages (inside encrypted payload)
 // ============================================================================
 
+/// Check the leading device message type byte
+fn expect_device_type(r: &mut Reader<'_>, expected: u8) -> Result<(), ProtoError> {
+    let got = r.u8()?;
+    if got != expected {
+        return Err(ProtoError::UnexpectedType { expected, got });
+    }
+    Ok(())
+}
+
 /// HANDSHAKE_INIT: First message from invite acceptor to invite creator
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandshakeInit {
     pub sender_id52: [u8; 32],
     pub preimage_for_peer: [u8; 32],
@@ -368,43 +668,28 @@ pub struct HandshakeInit {
 }
 
 impl HandshakeInit {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let url_bytes = self.relay_url.as_bytes();
-        let url_len = url_bytes.len() as u16;
-        let mut buf = Vec::with_capacity(1 + 32 + 32 + 2 + url_bytes.len());
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(1 + 32 + 32 + 2 + self.relay_url.len());
         buf.push(DEV_HANDSHAKE_INIT);
         buf.extend_from_slice(&self.sender_id52);
         buf.extend_from_slice(&self.preimage_for_peer);
-        buf.extend_from_slice(&url_len.to_be_bytes());
-        buf.extend_from_slice(url_bytes);
-        buf
+        put_string_u16(&mut buf, "HANDSHAKE_INIT relay_url", &self.relay_url)?;
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 1 + 32 + 32 + 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "HANDSHAKE_INIT too short"));
-        }
-        if data[0] != DEV_HANDSHAKE_INIT {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "not HANDSHAKE_INIT"));
-        }
-
-        let sender_id52: [u8; 32] = data[1..33].try_into().unwrap();
-        let preimage_for_peer: [u8; 32] = data[33..65].try_into().unwrap();
-        let url_len = u16::from_be_bytes([data[65], data[66]]) as usize;
-
-        if data.len() < 67 + url_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "HANDSHAKE_INIT url truncated"));
-        }
-
-        let relay_url = String::from_utf8(data[67..67 + url_len].to_vec())
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf8 in relay_url"))?;
-
-        Ok(Self { sender_id52, preimage_for_peer, relay_url })
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "HANDSHAKE_INIT");
+        expect_device_type(&mut r, DEV_HANDSHAKE_INIT)?;
+        Ok(Self {
+            sender_id52: r.array()?,
+            preimage_for_peer: r.array()?,
+            relay_url: r.string_u16("relay_url")?,
+        })
     }
 }
 
 /// HANDSHAKE_COMPLETE: Response from invite creator to acceptor
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandshakeComplete {
     pub status: u8,
     pub preimage_for_peer: [u8; 32],
@@ -412,43 +697,28 @@ pub struct HandshakeComplete {
 }
 
 impl HandshakeComplete {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let url_bytes = self.relay_url.as_bytes();
-        let url_len = url_bytes.len() as u16;
-        let mut buf = Vec::with_capacity(1 + 1 + 32 + 2 + url_bytes.len());
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(1 + 1 + 32 + 2 + self.relay_url.len());
         buf.push(DEV_HANDSHAKE_COMPLETE);
         buf.push(self.status);
         buf.extend_from_slice(&self.preimage_for_peer);
-        buf.extend_from_slice(&url_len.to_be_bytes());
-        buf.extend_from_slice(url_bytes);
-        buf
+        put_string_u16(&mut buf, "HANDSHAKE_COMPLETE relay_url", &self.relay_url)?;
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 1 + 1 + 32 + 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "HANDSHAKE_COMPLETE too short"));
-        }
-        if data[0] != DEV_HANDSHAKE_COMPLETE {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "not HANDSHAKE_COMPLETE"));
-        }
-
-        let status = data[1];
-        let preimage_for_peer: [u8; 32] = data[2..34].try_into().unwrap();
-        let url_len = u16::from_be_bytes([data[34], data[35]]) as usize;
-
-        if data.len() < 36 + url_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "HANDSHAKE_COMPLETE url truncated"));
-        }
-
-        let relay_url = String::from_utf8(data[36..36 + url_len].to_vec())
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf8 in relay_url"))?;
-
-        Ok(Self { status, preimage_for_peer, relay_url })
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "HANDSHAKE_COMPLETE");
+        expect_device_type(&mut r, DEV_HANDSHAKE_COMPLETE)?;
+        Ok(Self {
+            status: r.u8()?,
+            preimage_for_peer: r.array()?,
+            relay_url: r.string_u16("relay_url")?,
+        })
     }
 }
 
 /// MESSAGE: Application message between paired peers
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DeviceMessage {
     pub content_type: u8,
     pub relay_url: String,
@@ -467,56 +737,28 @@ impl DeviceMessage {
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let url_bytes = self.relay_url.as_bytes();
-        let url_len = url_bytes.len() as u16;
-        let content_len = self.content.len() as u32;
-        let mut buf = Vec::with_capacity(1 + 1 + 2 + url_bytes.len() + 4 + self.content.len());
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(1 + 1 + 2 + self.relay_url.len() + 4 + self.content.len());
         buf.push(DEV_MESSAGE);
         buf.push(self.content_type);
-        buf.extend_from_slice(&url_len.to_be_bytes());
-        buf.extend_from_slice(url_bytes);
-        buf.extend_from_slice(&content_len.to_be_bytes());
-        buf.extend_from_slice(&self.content);
-        buf
+        put_string_u16(&mut buf, "MESSAGE relay_url", &self.relay_url)?;
+        put_bytes_u32(&mut buf, "MESSAGE content", &self.content)?;
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 1 + 1 + 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "MESSAGE too short"));
-        }
-        if data[0] != DEV_MESSAGE {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "not MESSAGE"));
-        }
-
-        let content_type = data[1];
-        let url_len = u16::from_be_bytes([data[2], data[3]]) as usize;
-
-        if data.len() < 4 + url_len + 4 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "MESSAGE url truncated"));
-        }
-
-        let relay_url = String::from_utf8(data[4..4 + url_len].to_vec())
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf8 in relay_url"))?;
-
-        let content_start = 4 + url_len;
-        let content_len = u32::from_be_bytes([
-            data[content_start], data[content_start + 1],
-            data[content_start + 2], data[content_start + 3]
-        ]) as usize;
-
-        if data.len() < content_start + 4 + content_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "MESSAGE content truncated"));
-        }
-
-        let content = data[content_start + 4..content_start + 4 + content_len].to_vec();
-
-        Ok(Self { content_type, relay_url, content })
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "MESSAGE");
+        expect_device_type(&mut r, DEV_MESSAGE)?;
+        Ok(Self {
+            content_type: r.u8()?,
+            relay_url: r.string_u16("relay_url")?,
+            content: r.bytes_u32()?,
+        })
     }
 }
 
 /// MESSAGE_RESPONSE: Response to a message, includes next preimage
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DeviceMessageResponse {
     pub status: u8,
     pub next_preimage: [u8; 32],
@@ -525,53 +767,25 @@ pub struct DeviceMessageResponse {
 }
 
 impl DeviceMessageResponse {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let url_bytes = self.relay_url.as_bytes();
-        let url_len = url_bytes.len() as u16;
-        let content_len = self.content.len() as u32;
-        let mut buf = Vec::with_capacity(1 + 1 + 32 + 2 + url_bytes.len() + 4 + self.content.len());
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(1 + 1 + 32 + 2 + self.relay_url.len() + 4 + self.content.len());
         buf.push(DEV_MESSAGE_RESPONSE);
         buf.push(self.status);
         buf.extend_from_slice(&self.next_preimage);
-        buf.extend_from_slice(&url_len.to_be_bytes());
-        buf.extend_from_slice(url_bytes);
-        buf.extend_from_slice(&content_len.to_be_bytes());
-        buf.extend_from_slice(&self.content);
-        buf
+        put_string_u16(&mut buf, "MESSAGE_RESPONSE relay_url", &self.relay_url)?;
+        put_bytes_u32(&mut buf, "MESSAGE_RESPONSE content", &self.content)?;
+        Ok(buf)
     }
 
-    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 1 + 1 + 32 + 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "MESSAGE_RESPONSE too short"));
-        }
-        if data[0] != DEV_MESSAGE_RESPONSE {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "not MESSAGE_RESPONSE"));
-        }
-
-        let status = data[1];
-        let next_preimage: [u8; 32] = data[2..34].try_into().unwrap();
-        let url_len = u16::from_be_bytes([data[34], data[35]]) as usize;
-
-        if data.len() < 36 + url_len + 4 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "MESSAGE_RESPONSE url truncated"));
-        }
-
-        let relay_url = String::from_utf8(data[36..36 + url_len].to_vec())
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf8 in relay_url"))?;
-
-        let content_start = 36 + url_len;
-        let content_len = u32::from_be_bytes([
-            data[content_start], data[content_start + 1],
-            data[content_start + 2], data[content_start + 3]
-        ]) as usize;
-
-        if data.len() < content_start + 4 + content_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "MESSAGE_RESPONSE content truncated"));
-        }
-
-        let content = data[content_start + 4..content_start + 4 + content_len].to_vec();
-
-        Ok(Self { status, next_preimage, relay_url, content })
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "MESSAGE_RESPONSE");
+        expect_device_type(&mut r, DEV_MESSAGE_RESPONSE)?;
+        Ok(Self {
+            status: r.u8()?,
+            next_preimage: r.array()?,
+            relay_url: r.string_u16("relay_url")?,
+            content: r.bytes_u32()?,
+        })
     }
 }
 
@@ -585,7 +799,7 @@ pub fn parse_device_msg_type(data: &[u8]) -> Option<u8> {
 // ============================================================================
 
 /// Frame: wraps any message with type and length
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Frame {
     pub msg_type: u16,
     pub payload: Vec<u8>,
@@ -596,92 +810,286 @@ impl Frame {
         Self { msg_type, payload }
     }
 
-    pub fn hello(hello: &Hello) -> Self {
-        Self::new(MSG_HELLO, hello.to_bytes())
-    }
-
-    pub fn i_am(i_am: &IAm) -> Self {
-        Self::new(MSG_I_AM, i_am.to_bytes())
-    }
-
-    pub fn send(send: &Send) -> Self {
-        Self::new(MSG_SEND, send.to_bytes())
-    }
-
-    pub fn deliver(deliver: &Deliver) -> Self {
-        Self::new(MSG_DELIVER, deliver.to_bytes())
+    /// Decode the payload according to `msg_type`
+    pub fn decode(&self) -> Result<RelayMessage, ProtoError> {
+        let data = &self.payload;
+        Ok(match self.msg_type {
+            MSG_HELLO => RelayMessage::Hello(Hello::from_bytes(data)?),
+            MSG_I_AM => RelayMessage::IAm(IAm::from_bytes(data)?),
+            MSG_SEND => RelayMessage::Send(Send::from_bytes(data)?),
+            MSG_DELIVER => RelayMessage::Deliver(Deliver::from_bytes(data)?),
+            MSG_ACK => RelayMessage::Ack(Ack::from_bytes(data)?),
+            MSG_KEEPALIVE => RelayMessage::Keepalive,
+            MSG_SEND_RESULT => RelayMessage::SendResult(SendResult::from_bytes(data)?),
+            MSG_UPDATE_COMMITS => RelayMessage::UpdateCommits(UpdateCommits::from_bytes(data)?),
+            MSG_PRESENCE => RelayMessage::Presence(SignedPresence::from_bytes(data)?),
+            MSG_PRESENCE_QUERY => RelayMessage::PresenceQuery(PresenceQuery::from_bytes(data)?),
+            MSG_PRESENCE_RESULT => RelayMessage::PresenceResult(PresenceResult::from_bytes(data)?),
+            MSG_RELAY_PEER => RelayMessage::RelayPeer(RelayPeer::from_bytes(data)?),
+            MSG_REVOKE_COMMITS => RelayMessage::RevokeCommits(RevokeCommits::from_bytes(data)?),
+            MSG_NACK => RelayMessage::Nack(Nack::from_bytes(data)?),
+            other => return Err(ProtoError::UnknownType(other)),
+        })
     }
 
-    pub fn ack(ack: &Ack) -> Self {
-        Self::new(MSG_ACK, ack.to_bytes())
+    /// Frame header (type + length)
+    fn header(&self) -> Result<[u8; 6], ProtoError> {
+        let len = len_u32("frame", self.payload.len())?;
+        let mut header = [0u8; 6];
+        header[..2].copy_from_slice(&self.msg_type.to_be_bytes());
+        header[2..].copy_from_slice(&len.to_be_bytes());
+        Ok(header)
     }
 
-    pub fn send_result(result: &SendResult) -> Self {
-        Self::new(MSG_SEND_RESULT, result.to_bytes())
+    /// Parse a frame header, rejecting payloads over `max_payload`
+    fn parse_header(header: &[u8; 6], max_payload: usize) -> Result<(u16, usize), ProtoError> {
+        let msg_type = u16::from_be_bytes([header[0], header[1]]);
+        let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+        if len > max_payload {
+            return Err(ProtoError::Oversized { what: "frame", len, max: max_payload });
+        }
+        Ok((msg_type, len))
     }
 
-    pub fn update_commits(update: &UpdateCommits) -> Self {
-        Self::new(MSG_UPDATE_COMMITS, update.to_bytes())
+    /// Serialize header and payload
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(6 + self.payload.len());
+        buf.extend_from_slice(&self.header()?);
+        buf.extend_from_slice(&self.payload);
+        Ok(buf)
     }
 
     /// Write frame to a writer
+    #[cfg(feature = "std")]
     pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        let len = self.payload.len() as u32;
-        writer.write_all(&self.msg_type.to_be_bytes())?;
-        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(&self.header()?)?;
         writer.write_all(&self.payload)?;
         Ok(())
     }
 
     /// Read frame from a reader
+    #[cfg(feature = "std")]
     pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
         let mut header = [0u8; 6];
         reader.read_exact(&mut header)?;
+        let (msg_type, len) = Self::parse_header(&header, MAX_FRAME_SIZE)?;
 
-        let msg_type = u16::from_be_bytes([header[0], header[1]]);
-        let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
-
-        // Sanity check
-        if len > 1024 * 1024 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
-        }
-
-        let mut payload = vec![0u8; len];
+        let mut payload = alloc::vec![0u8; len];
         reader.read_exact(&mut payload)?;
 
         Ok(Self { msg_type, payload })
     }
 }
 
-/// Async frame operations for tokio
-#[cfg(feature = "async")]
+/// Async frame operations for tokio, one frame at a time through
+/// [`BhumiCodec`]; for a long-lived stream use the codec with `Framed`
+#[cfg(feature = "tokio")]
 pub mod async_io {
     use super::*;
+    use bytes::BytesMut;
     use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio_util::codec::{Decoder, Encoder};
 
     pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> io::Result<()> {
-        let len = frame.payload.len() as u32;
-        writer.write_all(&frame.msg_type.to_be_bytes()).await?;
-        writer.write_all(&len.to_be_bytes()).await?;
-        writer.write_all(&frame.payload).await?;
-        writer.flush().await?;
-        Ok(())
+        let mut buf = BytesMut::new();
+        BhumiCodec::new().encode(frame.clone(), &mut buf)?;
+        writer.write_all(&buf).await?;
+        writer.flush().await
+    }
+
+    /// Encode and write a message
+    pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, msg: impl Into<RelayMessage>) -> io::Result<()> {
+        write_frame(writer, &msg.into().encode()?).await
     }
 
+    /// Read exactly one frame, so nothing past it is consumed from `reader`
     pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+        let mut codec = BhumiCodec::new();
         let mut header = [0u8; 6];
         reader.read_exact(&mut header).await?;
+        let (_, len) = Frame::parse_header(&header, codec.max_payload())?;
+
+        let mut buf = BytesMut::with_capacity(6 + len);
+        buf.extend_from_slice(&header);
+        buf.resize(6 + len, 0);
+        reader.read_exact(&mut buf[6..]).await?;
+        let frame = codec.decode(&mut buf)?;
+        Ok(frame.expect("a whole frame was read"))
+    }
+}
 
-        let msg_type = u16::from_be_bytes([header[0], header[1]]);
-        let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let messages = vec![
+            RelayMessage::Hello(Hello::new(7, 64 * 1024, CAP_KEEPALIVE)),
+            RelayMessage::IAm(IAm {
+                id52: [1; 32],
+                signature: [2; 64],
+                commits: vec![[3; 32], [4; 32]],
+                recent_responses: vec![RecentResponse { preimage: [5; 32], response: b"ok".to_vec() }],
+                version: PROTOCOL_VERSION,
+                capabilities: CAP_KEEPALIVE | CAP_ENCRYPTION_REQUIRED,
+            }),
+            RelayMessage::Send(Send { to_id52: [6; 32], preimage: [7; 32], payload: b"hi".to_vec(), request_id: 3, deadline_ms: 500 }),
+            RelayMessage::Deliver(Deliver { msg_id: 9, preimage: [8; 32], payload: vec![] }),
+            RelayMessage::Ack(Ack { msg_id: 9, payload: b"resp".to_vec() }),
+            RelayMessage::Nack(Nack { msg_id: 9, reason: NACK_BUSY }),
+            RelayMessage::Keepalive,
+            RelayMessage::SendResult(SendResult::error(SEND_ERR_TIMEOUT).with_request_id(3)),
+            RelayMessage::UpdateCommits(UpdateCommits::new(vec![[9; 32]])),
+            RelayMessage::UpdateCommits(UpdateCommits { commits: vec![[9; 32], [10; 32]], ttl_secs: vec![0, 600] }),
+            RelayMessage::RevokeCommits(RevokeCommits { commits: vec![[9; 32]] }),
+            RelayMessage::PresenceQuery(PresenceQuery { id52: [10; 32] }),
+            RelayMessage::RelayPeer(RelayPeer { relay_id52: [13; 32], signature: [14; 64] }),
+            RelayMessage::PresenceResult(PresenceResult { presence: None }),
+            RelayMessage::PresenceResult(PresenceResult {
+                presence: Some(SignedPresence {
+                    presence: Presence { id52: [11; 32], relay_id52: [13; 32], issued_at: 1, ttl_secs: 60 },
+                    signature: [12; 64],
+                }),
+            }),
+        ];
+        for msg in messages {
+            let frame = msg.encode().unwrap();
+            assert_eq!(frame.msg_type, msg.msg_type());
+            assert_eq!(frame.decode().unwrap(), msg);
+        }
+    }
 
-        if len > 1024 * 1024 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    #[test]
+    fn decode_errors() {
+        let frame = Frame::new(MSG_SEND, vec![0; 10]);
+        assert_eq!(frame.decode(), Err(ProtoError::Truncated { msg: "SEND" }));
+
+        // Declared payload length runs past the end of the frame
+        let mut payload = vec![0; 64];
+        payload.extend_from_slice(&100u32.to_be_bytes());
+        let frame = Frame::new(MSG_SEND, payload);
+        assert_eq!(frame.decode(), Err(ProtoError::Truncated { msg: "SEND" }));
+
+        assert_eq!(Frame::new(0x7777, vec![]).decode(), Err(ProtoError::UnknownType(0x7777)));
+
+        let mut init = vec![DEV_HANDSHAKE_INIT];
+        init.extend_from_slice(&[0; 64]);
+        init.extend_from_slice(&2u16.to_be_bytes());
+        init.extend_from_slice(&[0xff, 0xfe]);
+        assert_eq!(HandshakeInit::from_bytes(&init), Err(ProtoError::InvalidUtf8 { field: "relay_url" }));
+
+        #[cfg(feature = "std")]
+        {
+            let header = [0, 1, 0xff, 0xff, 0xff, 0xff];
+            assert!(matches!(Frame::read_from(&mut &header[..]), Err(e) if e.kind() == io::ErrorKind::InvalidData));
         }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_io_reads_one_frame_at_a_time() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let ack = RelayMessage::Ack(Ack { msg_id: 3, payload: b"ok".to_vec() });
+        async_io::write_message(&mut a, RelayMessage::Keepalive).await.unwrap();
+        async_io::write_message(&mut a, ack.clone()).await.unwrap();
+        assert_eq!(async_io::read_frame(&mut b).await.unwrap().decode().unwrap(), RelayMessage::Keepalive);
+        assert_eq!(async_io::read_frame(&mut b).await.unwrap().decode().unwrap(), ack);
+
+        let oversized = [0, 1, 0xff, 0xff, 0xff, 0xff];
+        let err = async_io::read_frame(&mut &oversized[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
 
-        let mut payload = vec![0u8; len];
-        reader.read_exact(&mut payload).await?;
+    #[cfg(feature = "std")]
+    #[test]
+    fn signed_hello() {
+        let key = fastn_id52::SecretKey::generate();
+        let hello = Hello::new(7, 1024, CAP_KEEPALIVE).sign(&key);
+        let parsed = Hello::from_bytes(&hello.to_bytes()).unwrap();
+        assert_eq!(parsed.verified_relay_id52(), Some(key.public_key()));
+
+        // Capabilities can't be changed without breaking the signature
+        let mut tampered = parsed.clone();
+        tampered.capabilities = 0;
+        assert_eq!(tampered.verified_relay_id52(), None);
+        assert_eq!(Hello::new(7, 1024, 0).verified_relay_id52(), None);
+
+        let bytes = hello.to_bytes();
+        assert_eq!(Hello::from_bytes(&bytes[..bytes.len() - 1]), Err(ProtoError::Truncated { msg: "HELLO" }));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn signed_relay_peer() {
+        let key = fastn_id52::SecretKey::generate();
+        let peer = RelayPeer::from_bytes(&RelayPeer::sign(7, &key).to_bytes()).unwrap();
+        assert_eq!(peer.verified_relay_id52(7), Some(key.public_key()));
+
+        // Bound to the session's nonce, so it can't be replayed on another
+        assert_eq!(peer.verified_relay_id52(8), None);
+        let mut claimed = peer.clone();
+        claimed.relay_id52 = fastn_id52::SecretKey::generate().public_key().to_bytes();
+        assert_eq!(claimed.verified_relay_id52(7), None);
+    }
+
+    #[test]
+    fn version_1_frames_parse_without_capabilities() {
+        let mut hello = vec![1];
+        hello.extend_from_slice(&7u32.to_be_bytes());
+        hello.extend_from_slice(&1024u32.to_be_bytes());
+        let hello = Hello::from_bytes(&hello).unwrap();
+        assert_eq!((hello.version, hello.capabilities), (1, 0));
+
+        let i_am = IAm::new([1; 32], [2; 64], vec![[3; 32]]).with_capabilities(CAP_KEEPALIVE);
+        let mut bytes = i_am.to_bytes().unwrap();
+        bytes.truncate(bytes.len() - 5);
+        let old = IAm::from_bytes(&bytes).unwrap();
+        assert_eq!((old.version, old.capabilities), (1, 0));
+        assert!(!old.supports(CAP_KEEPALIVE));
+        assert_eq!(old.commits, i_am.commits);
+
+        // A cut-off capability field is malformed, not version 1
+        bytes.extend_from_slice(&[2, 0]);
+        assert_eq!(IAm::from_bytes(&bytes), Err(ProtoError::Truncated { msg: "I_AM" }));
+    }
+
+    #[test]
+    fn request_id_and_deadline_are_optional() {
+        let send = Send { to_id52: [1; 32], preimage: [2; 32], payload: b"hi".to_vec(), request_id: 5, deadline_ms: 500 };
+        let mut bytes = send.to_bytes().unwrap();
+        bytes.truncate(bytes.len() - 4);
+        assert_eq!(Send::from_bytes(&bytes).unwrap(), Send { deadline_ms: 0, ..send.clone() });
+        bytes.truncate(bytes.len() - 4);
+        assert_eq!(Send::from_bytes(&bytes).unwrap(), Send { request_id: 0, deadline_ms: 0, ..send });
+        let mut bytes = SendResult::success(b"ok".to_vec()).with_request_id(5).to_bytes().unwrap();
+        bytes.truncate(bytes.len() - 4);
+        assert_eq!(SendResult::from_bytes(&bytes).unwrap(), SendResult::success(b"ok".to_vec()));
+    }
+
+    #[test]
+    fn commit_ttls_are_optional() {
+        let plain = UpdateCommits::new(vec![[1; 32], [2; 32]]);
+        assert_eq!(plain.to_bytes().unwrap().len(), 2 + 64);
+        assert_eq!(plain.ttl(0), None);
+
+        let expiring = plain.with_ttl(60);
+        let bytes = expiring.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 2 + 64 + 8);
+        assert_eq!(UpdateCommits::from_bytes(&bytes).unwrap().ttl(1), Some(60));
+        assert_eq!(
+            UpdateCommits::from_bytes(&bytes[..bytes.len() - 4]),
+            Err(ProtoError::Truncated { msg: "UPDATE_COMMITS" })
+        );
+    }
 
-        Ok(Frame { msg_type, payload })
+    #[test]
+    fn encode_rejects_overflowing_counts() {
+        let update = UpdateCommits::new(vec![[0; 32]; u16::MAX as usize + 1]);
+        assert_eq!(
+            RelayMessage::from(update).encode(),
+            Err(ProtoError::Oversized { what: "UPDATE_COMMITS commits", len: 65536, max: 65535 })
+        );
     }
 }
@@ -0,0 +1,231 @@
+//! PRESENCE assertions (relay protocol §7.1)
+//!
+//! A device signs "I can be reached at relay `relay_id52` from `issued_at` for
+//! `ttl_secs` seconds". Only the id52 owner can produce one; relays store and
+//! hand them out but cannot mint or extend them.
+//!
+//! ```text
+//! PRESENCE {
+//!     bytes[32] id52
+//!     bytes[32] relay_id52      // identity the relay signed its HELLO with
+//!     u64       issued_at       // unix seconds
+//!     u32       ttl_secs
+//! }
+//! SIGNED_PRESENCE { PRESENCE, bytes[64] signature }  // Sign(id52, SHA256(PRESENCE))
+//! ```
+
+use alloc::vec::Vec;
+use core::fmt;
+use sha2::{Digest, Sha256};
+
+use crate::ProtoError;
+use crate::wire::Reader;
+
+/// Allowed clock skew when checking `issued_at` against our clock
+pub const PRESENCE_CLOCK_SKEW_SECS: u64 = 60;
+
+/// Unsigned presence statement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub id52: [u8; 32],
+    pub relay_id52: [u8; 32],
+    pub issued_at: u64,
+    pub ttl_secs: u32,
+}
+
+impl Presence {
+    /// Unix time after which this presence must not be used
+    pub fn expires_at(&self) -> u64 {
+        self.issued_at.saturating_add(self.ttl_secs as u64)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = Vec::with_capacity(32 + 32 + 8 + 4);
+        buf.extend_from_slice(&self.id52);
+        buf.extend_from_slice(&self.relay_id52);
+        buf.extend_from_slice(&self.issued_at.to_be_bytes());
+        buf.extend_from_slice(&self.ttl_secs.to_be_bytes());
+        Ok(buf)
+    }
+
+    fn read(r: &mut Reader<'_>) -> Result<Self, ProtoError> {
+        Ok(Self {
+            id52: r.array()?,
+            relay_id52: r.array()?,
+            issued_at: u64::from_be_bytes(r.array()?),
+            ttl_secs: r.u32()?,
+        })
+    }
+
+    /// SHA256 of the encoded statement: the message the id52 owner signs
+    pub fn digest(&self) -> Result<[u8; 32], ProtoError> {
+        Ok(Sha256::digest(self.to_bytes()?).into())
+    }
+
+    /// Sign as the id52 owner (`secret_key` must belong to `self.id52`)
+    #[cfg(feature = "std")]
+    pub fn sign(self, secret_key: &fastn_id52::SecretKey) -> Result<SignedPresence, ProtoError> {
+        let signature = secret_key.sign(&self.digest()?).to_bytes();
+        Ok(SignedPresence { presence: self, signature })
+    }
+}
+
+/// Current unix time in seconds, as used for `issued_at`
+#[cfg(feature = "std")]
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Why a signed presence was not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceError {
+    /// Signature doesn't verify against `id52`
+    BadSignature,
+    /// `issued_at + ttl_secs` is in the past
+    Expired,
+    /// `issued_at` is further in the future than the allowed clock skew
+    NotYetValid,
+}
+
+impl fmt::Display for PresenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceError::BadSignature => write!(f, "presence signature invalid"),
+            PresenceError::Expired => write!(f, "presence expired"),
+            PresenceError::NotYetValid => write!(f, "presence issued in the future"),
+        }
+    }
+}
+
+impl core::error::Error for PresenceError {}
+
+/// Presence with the owner's signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedPresence {
+    pub presence: Presence,
+    pub signature: [u8; 64],
+}
+
+impl SignedPresence {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        let mut buf = self.presence.to_bytes()?;
+        buf.extend_from_slice(&self.signature);
+        Ok(buf)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        Self::read(&mut Reader::new(data, "PRESENCE"))
+    }
+
+    pub(crate) fn read(r: &mut Reader<'_>) -> Result<Self, ProtoError> {
+        Ok(Self {
+            presence: Presence::read(r)?,
+            signature: r.array()?,
+        })
+    }
+
+    /// Check the signature and that the presence is valid at unix time `now`
+    #[cfg(feature = "std")]
+    pub fn verify(&self, now: u64) -> Result<(), PresenceError> {
+        let public_key = fastn_id52::PublicKey::from_bytes(&self.presence.id52)
+            .map_err(|_| PresenceError::BadSignature)?;
+        let signature = fastn_id52::Signature::from_bytes(&self.signature)
+            .map_err(|_| PresenceError::BadSignature)?;
+        let digest = self.presence.digest().map_err(|_| PresenceError::BadSignature)?;
+        public_key.verify(&digest, &signature).map_err(|_| PresenceError::BadSignature)?;
+
+        if self.presence.issued_at > now.saturating_add(PRESENCE_CLOCK_SKEW_SECS) {
+            return Err(PresenceError::NotYetValid);
+        }
+        if self.presence.expires_at() <= now {
+            return Err(PresenceError::Expired);
+        }
+        Ok(())
+    }
+}
+
+/// PRESENCE_QUERY - ask a relay for the latest presence of an id52
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceQuery {
+    pub id52: [u8; 32],
+}
+
+impl PresenceQuery {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.id52.to_vec()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "PRESENCE_QUERY");
+        Ok(Self { id52: r.array()? })
+    }
+}
+
+/// PRESENCE_RESULT - relay's answer to PRESENCE_QUERY (unverified: check it yourself)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceResult {
+    pub presence: Option<SignedPresence>,
+}
+
+impl PresenceResult {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtoError> {
+        match &self.presence {
+            Some(p) => {
+                let mut buf = alloc::vec![1];
+                buf.extend_from_slice(&p.to_bytes()?);
+                Ok(buf)
+            }
+            None => Ok(alloc::vec![0]),
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data, "PRESENCE_RESULT");
+        let presence = match r.u8()? {
+            0 => None,
+            _ => Some(SignedPresence::read(&mut r)?),
+        };
+        Ok(Self { presence })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn presence(secret_key: &fastn_id52::SecretKey, issued_at: u64) -> Presence {
+        Presence {
+            id52: secret_key.public_key().to_bytes(),
+            relay_id52: [7; 32],
+            issued_at,
+            ttl_secs: 300,
+        }
+    }
+
+    #[test]
+    fn sign_verify() {
+        let key = fastn_id52::SecretKey::generate();
+        let signed = presence(&key, 1000).sign(&key).unwrap();
+        assert_eq!(signed.verify(1000), Ok(()));
+        assert_eq!(SignedPresence::from_bytes(&signed.to_bytes().unwrap()).unwrap(), signed);
+
+        assert_eq!(signed.verify(1300), Err(PresenceError::Expired));
+        assert_eq!(signed.verify(1000 - PRESENCE_CLOCK_SKEW_SECS - 1), Err(PresenceError::NotYetValid));
+    }
+
+    #[test]
+    fn relay_cannot_extend() {
+        let key = fastn_id52::SecretKey::generate();
+        let mut signed = presence(&key, 1000).sign(&key).unwrap();
+        signed.presence.ttl_secs = 3600;
+        assert_eq!(signed.verify(1000), Err(PresenceError::BadSignature));
+
+        // Signed by someone other than id52
+        let other = fastn_id52::SecretKey::generate();
+        let forged = presence(&key, 1000).sign(&other).unwrap();
+        assert_eq!(forged.verify(1000), Err(PresenceError::BadSignature));
+    }
+}
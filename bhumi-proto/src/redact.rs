@@ -0,0 +1,92 @@
+//! id52 redaction for logs
+//!
+//! Logs name devices through [`Id52`], which prints according to the
+//! process-wide [`Redaction`] mode. Preimages have no such wrapper: they
+//! must never be logged.
+
+use core::fmt;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+
+/// How id52s appear in logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Redaction {
+    /// Full id52
+    Off,
+    /// First 8 characters, e.g. `akmtb3e5…`
+    Truncate,
+    /// Keyed hash, e.g. `#3f9c01d2a7b4`: stable for the life of the process
+    /// (so one device's lines can be followed) but not across restarts
+    #[default]
+    Hash,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Redaction::Hash as u8);
+
+pub fn set_redaction(mode: Redaction) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn redaction() -> Redaction {
+    match MODE.load(Ordering::Relaxed) {
+        0 => Redaction::Off,
+        1 => Redaction::Truncate,
+        _ => Redaction::Hash,
+    }
+}
+
+/// Displays an id52 as the current [`Redaction`] mode allows
+#[derive(Clone, Copy)]
+pub struct Id52<'a>(pub &'a [u8; 32]);
+
+impl fmt::Display for Id52<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match redaction() {
+            Redaction::Off => f.write_str(&data_encoding::BASE32_DNSSEC.encode(self.0)),
+            Redaction::Truncate => write!(f, "{}…", &data_encoding::BASE32_DNSSEC.encode(&self.0[..5])),
+            Redaction::Hash => {
+                static KEY: OnceLock<[u8; 16]> = OnceLock::new();
+                let key = KEY.get_or_init(|| {
+                    let mut key = [0; 16];
+                    rand_core::OsRng.fill_bytes(&mut key);
+                    key
+                });
+                let digest = Sha256::new().chain_update(key).chain_update(self.0).finalize();
+                write!(f, "#{}", data_encoding::HEXLOWER.encode(&digest[..6]))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Id52<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn modes() {
+        let id52 = [7u8; 32];
+        let full = data_encoding::BASE32_DNSSEC.encode(&id52);
+
+        set_redaction(Redaction::Off);
+        assert_eq!(Id52(&id52).to_string(), full);
+
+        set_redaction(Redaction::Truncate);
+        assert_eq!(Id52(&id52).to_string(), format!("{}…", &full[..8]));
+
+        set_redaction(Redaction::Hash);
+        let hashed = Id52(&id52).to_string();
+        assert_eq!(hashed.len(), 13);
+        assert_eq!(hashed, Id52(&id52).to_string());
+        assert_ne!(hashed, Id52(&[8; 32]).to_string());
+    }
+}
@@ -0,0 +1,203 @@
+//! TLS transport for `tls://` relay addresses (see [`crate::addr`])
+//!
+//! There is no system trust store: every TLS address names how the relay is
+//! authenticated, so a device on hostile Wi-Fi can't be talked into
+//! registering commits with an impostor, and a relay can't be talked into
+//! gossiping to one.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+
+use sha2::{Digest, Sha256};
+
+use crate::addr::{RelayAddr, RelayPin, invalid};
+
+/// Byte stream to a relay (TCP or TLS over TCP)
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+impl RelayAddr {
+    /// Open a TCP or TLS stream as the address says, checking the pin. The
+    /// HELLO signature (`relay_id52`) is left to whoever speaks the protocol
+    /// over it, as bhumi-node's `Connection` does.
+    pub async fn connect(&self) -> std::io::Result<Box<dyn Transport>> {
+        connect(self).await
+    }
+}
+
+/// Open a TCP or TLS stream as the address says
+async fn connect(addr: &RelayAddr) -> std::io::Result<Box<dyn Transport>> {
+    let tcp = TcpStream::connect(&addr.addr).await?;
+    let Some(pin) = &addr.tls else {
+        return Ok(Box::new(tcp));
+    };
+
+    let connector = TlsConnector::from(Arc::new(client_config(pin)?));
+    let server_name = ServerName::try_from(addr.host.clone())
+        .map_err(|e| invalid(format!("{}: {e}", addr.host)))?;
+    Ok(Box::new(connector.connect(server_name, tcp).await?))
+}
+
+fn client_config(pin: &RelayPin) -> std::io::Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let config = match pin {
+        RelayPin::Ca(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                roots.add(cert?).map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+            }
+            if roots.is_empty() {
+                return Err(invalid(format!("no CA certificates in {}", path.display())));
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        _ => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinVerifier { pin: pin.clone(), provider }))
+            .with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+/// Accepts exactly the pinned certificate or key, whatever its chain or name
+#[derive(Debug)]
+struct PinVerifier {
+    pin: RelayPin,
+    provider: Arc<CryptoProvider>,
+}
+
+/// DER SubjectPublicKeyInfo prefix for an Ed25519 key (RFC 8410)
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let matches = match &self.pin {
+            RelayPin::CertSha256(fingerprint) => Sha256::digest(end_entity.as_ref()).as_slice() == fingerprint,
+            RelayPin::Id52(id52) => {
+                let cert = webpki::EndEntityCert::try_from(end_entity)
+                    .map_err(|_| rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding))?;
+                let spki = cert.subject_public_key_info();
+                spki.as_ref().strip_prefix(&ED25519_SPKI_PREFIX) == Some(&id52.to_bytes()[..])
+            }
+            RelayPin::Ca(_) => false,
+        };
+        if !matches {
+            return Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // The handshake signature still has to verify against the pinned key
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastn_id52::PublicKey;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+    /// TLS echo-one-byte server with an Ed25519 self-signed certificate
+    async fn server() -> (String, rcgen::CertifiedKey) {
+        let key_pair = rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::try_from(key_pair.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut tls) = acceptor.accept(stream).await {
+                    let _ = tls.write_all(b"!").await;
+                }
+            }
+        });
+        (addr.replace("127.0.0.1", "localhost"), rcgen::CertifiedKey { cert, key_pair })
+    }
+
+    async fn try_connect(addr: &str) -> std::io::Result<u8> {
+        let mut stream = RelayAddr::parse(addr)?.connect().await?;
+        stream.read_u8().await
+    }
+
+    #[tokio::test]
+    async fn pins() {
+        let (addr, certified) = server().await;
+        let id52 = PublicKey::from_bytes(certified.key_pair.public_key_raw().try_into().unwrap()).unwrap();
+        let fingerprint = data_encoding::HEXLOWER.encode(&Sha256::digest(certified.cert.der()));
+
+        assert_eq!(try_connect(&format!("tls://{addr}?id52={id52}")).await.unwrap(), b'!');
+        assert_eq!(try_connect(&format!("tls://{addr}?sha256={fingerprint}")).await.unwrap(), b'!');
+
+        let ca = std::env::temp_dir().join(format!("bhumi-proto-tls-{}.pem", std::process::id()));
+        std::fs::write(&ca, certified.cert.pem()).unwrap();
+        assert_eq!(try_connect(&format!("tls://{addr}?ca={}", ca.display())).await.unwrap(), b'!');
+        let _ = std::fs::remove_file(&ca);
+
+        // Someone else's identity or certificate is refused
+        let other = fastn_id52::SecretKey::generate().public_key();
+        assert!(try_connect(&format!("tls://{addr}?id52={other}")).await.is_err());
+        assert!(try_connect(&format!("tls://{addr}?sha256={}", "00".repeat(32))).await.is_err());
+        assert!(RelayAddr::parse(&format!("tls://{addr}")).is_err());
+
+        // Plain addresses may name the relay identity for the HELLO check
+        assert_eq!(RelayAddr::parse(&format!("{addr}?id52={other}")).unwrap().relay_id52, Some(other));
+        assert_eq!(RelayAddr::parse(&format!("tls://{addr}?id52={id52}")).unwrap().relay_id52, Some(id52));
+        assert!(RelayAddr::parse(&format!("{addr}?sha256=00")).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Big-endian field readers and writers shared by all message types
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::ProtoError;
+
+/// Cursor over a message body; every read reports truncation against `msg`
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    msg: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8], msg: &'static str) -> Self {
+        Self { data, pos: 0, msg }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], ProtoError> {
+        if self.remaining() < len {
+            return Err(ProtoError::Truncated { msg: self.msg });
+        }
+        let out = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(out)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], ProtoError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, ProtoError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, ProtoError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, ProtoError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    /// `u32` length followed by that many bytes
+    pub(crate) fn bytes_u32(&mut self) -> Result<Vec<u8>, ProtoError> {
+        let len = self.u32()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    /// `u16` length followed by that many UTF-8 bytes
+    pub(crate) fn string_u16(&mut self, field: &'static str) -> Result<String, ProtoError> {
+        let len = self.u16()? as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtoError::InvalidUtf8 { field })
+    }
+
+    /// `u16` count followed by that many 32-byte values
+    pub(crate) fn array32s_u16(&mut self) -> Result<Vec<[u8; 32]>, ProtoError> {
+        let count = self.u16()? as usize;
+        if self.remaining() < count * 32 {
+            return Err(ProtoError::Truncated { msg: self.msg });
+        }
+        (0..count).map(|_| self.array()).collect()
+    }
+}
+
+/// Length that must fit in a `u16` field
+pub(crate) fn len_u16(what: &'static str, len: usize) -> Result<u16, ProtoError> {
+    u16::try_from(len).map_err(|_| ProtoError::Oversized { what, len, max: u16::MAX as usize })
+}
+
+/// Length that must fit in a `u32` field
+pub(crate) fn len_u32(what: &'static str, len: usize) -> Result<u32, ProtoError> {
+    u32::try_from(len).map_err(|_| ProtoError::Oversized { what, len, max: u32::MAX as usize })
+}
+
+pub(crate) fn put_bytes_u32(buf: &mut Vec<u8>, what: &'static str, bytes: &[u8]) -> Result<(), ProtoError> {
+    buf.extend_from_slice(&len_u32(what, bytes.len())?.to_be_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+pub(crate) fn put_string_u16(buf: &mut Vec<u8>, what: &'static str, s: &str) -> Result<(), ProtoError> {
+    buf.extend_from_slice(&len_u16(what, s.len())?.to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+pub(crate) fn put_array32s_u16(buf: &mut Vec<u8>, what: &'static str, items: &[[u8; 32]]) -> Result<(), ProtoError> {
+    buf.extend_from_slice(&len_u16(what, items.len())?.to_be_bytes());
+    for item in items {
+        buf.extend_from_slice(item);
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! SEND throughput through the router with 10k connected devices, each
+//! answering its DELIVERs at once:
+//!
+//!     cargo bench -p bhumi-relay --bench router
+//!
+//! One iteration is a SEND to every device, from 256 concurrent senders.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use bhumi_proto::SEND_OK;
+use bhumi_relay::config::{Cache, Config};
+use bhumi_relay::router::{PendingDelivery, Router};
+
+const DEVICES: u32 = 10_000;
+const SENDERS: u32 = 256;
+
+fn id52(device: u32) -> [u8; 32] {
+    let mut id52 = [0xff; 32];
+    id52[..4].copy_from_slice(&device.to_be_bytes());
+    id52
+}
+
+fn preimage(device: u32, round: u32) -> [u8; 32] {
+    let mut preimage = [0; 32];
+    preimage[..4].copy_from_slice(&device.to_be_bytes());
+    preimage[4..8].copy_from_slice(&round.to_be_bytes());
+    preimage
+}
+
+fn commit(preimage: [u8; 32]) -> [u8; 32] {
+    Sha256::digest(preimage).into()
+}
+
+fn send_throughput(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    // Room for every response, so what's measured is the router rather
+    // than cache eviction
+    let config = Config {
+        cache: Cache { max_entries: usize::MAX, max_bytes: usize::MAX, ..Cache::default() },
+        ..Config::default()
+    };
+    let router = Router::new(&config, Arc::new(fastn_id52::SecretKey::generate()));
+    for device in 0..DEVICES {
+        let (tx, mut rx) = mpsc::channel::<PendingDelivery>(32);
+        router.register(id52(device), Vec::new(), Vec::new(), 0, tx, CancellationToken::new());
+        let router = router.clone();
+        runtime.spawn(async move {
+            while let Some(delivery) = rx.recv().await {
+                router.handle_ack(&id52(device), delivery.msg_id, delivery.payload);
+            }
+        });
+    }
+
+    let mut group = c.benchmark_group("router");
+    group.throughput(Throughput::Elements(DEVICES as u64));
+    group.sample_size(10);
+    let mut round = 0;
+    group.bench_function("send_to_10k_devices", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                // A fresh commit per device, outside the timed part
+                for device in 0..DEVICES {
+                    assert!(router.add_commits(&id52(device), vec![(commit(preimage(device, round)), None)]));
+                }
+                let started = Instant::now();
+                runtime.block_on(async {
+                    let mut senders = tokio::task::JoinSet::new();
+                    for sender in 0..SENDERS {
+                        let router = router.clone();
+                        senders.spawn(async move {
+                            for device in (sender..DEVICES).step_by(SENDERS as usize) {
+                                let outcome = router.route_message(id52(device), preimage(device, round), vec![0; 64], 0, false).await;
+                                assert_eq!(outcome.status, SEND_OK);
+                            }
+                        });
+                    }
+                    senders.join_all().await;
+                });
+                elapsed += started.elapsed();
+                round += 1;
+            }
+            elapsed
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, send_throughput);
+criterion_main!(benches);
@@ -0,0 +1,222 @@
+//! Abuse scoring: SENDs that fail with an invalid preimage or to a recipient
+//! that isn't connected count against their source address
+//!
+//! Each failure adds a point to the address's score, which halves every
+//! `decay_secs`. Above `delay_after` SENDs from the address are delayed;
+//! reaching `ban_after` bans it, for `ban_secs` doubling with each repeat
+//! ban up to `max_ban_secs`. Bans can also be set and lifted by hand through
+//! the admin listener.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::Abuse as AbuseConfig;
+
+struct Record {
+    score: f64,
+    scored_at: Instant,
+    /// Automatic bans so far, for the escalating ban length
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Record {
+    fn new(now: Instant) -> Self {
+        Self { score: 0.0, scored_at: now, strikes: 0, banned_until: None }
+    }
+
+    fn decay(&mut self, config: &AbuseConfig, now: Instant) {
+        let periods = now.duration_since(self.scored_at).as_secs_f64() / config.decay_secs as f64;
+        self.score *= 0.5f64.powf(periods);
+        self.scored_at = now;
+    }
+
+    fn banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+/// What to do with a SEND (or connection) from an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Penalty {
+    None,
+    Delay(Duration),
+    Banned,
+}
+
+pub struct AbuseTracker {
+    config: AbuseConfig,
+    records: Mutex<HashMap<IpAddr, Record>>,
+}
+
+impl AbuseTracker {
+    pub fn new(config: AbuseConfig) -> Self {
+        Self { config, records: Mutex::new(HashMap::new()) }
+    }
+
+    fn exempt(&self, ip: IpAddr) -> bool {
+        self.config.exempt.contains(&ip)
+    }
+
+    /// Current penalty for `ip`
+    pub fn penalty(&self, ip: IpAddr) -> Penalty {
+        if self.exempt(ip) {
+            return Penalty::None;
+        }
+        let mut records = self.records.lock().unwrap();
+        let Some(record) = records.get_mut(&ip) else {
+            return Penalty::None;
+        };
+        let now = Instant::now();
+        if record.banned(now) {
+            return Penalty::Banned;
+        }
+        record.decay(&self.config, now);
+        let over = record.score - self.config.delay_after as f64;
+        if over <= 0.0 {
+            return Penalty::None;
+        }
+        let delay = Duration::from_millis((over * self.config.delay_step_ms as f64) as u64);
+        Penalty::Delay(delay.min(Duration::from_millis(self.config.max_delay_ms)))
+    }
+
+    /// Count a failed SEND against `ip`; true if this got it banned
+    pub fn record_failure(&self, ip: IpAddr) -> bool {
+        if self.exempt(ip) {
+            return false;
+        }
+        let mut records = self.records.lock().unwrap();
+        let now = Instant::now();
+        self.sweep(&mut records, now);
+        let record = records.entry(ip).or_insert_with(|| Record::new(now));
+        record.decay(&self.config, now);
+        record.score += 1.0;
+        // Rounded, as decay since the last failure leaves it just under
+        if record.score.round() < self.config.ban_after as f64 || record.banned(now) {
+            return false;
+        }
+
+        let secs = self.config.ban_secs.saturating_mul(1 << record.strikes.min(32)).min(self.config.max_ban_secs);
+        record.banned_until = Some(now + Duration::from_secs(secs));
+        record.strikes += 1;
+        record.score = 0.0;
+        warn!("Abuse: banned {} for {}s", ip, secs);
+        true
+    }
+
+    /// Ban `ip` by hand (not counted as a strike)
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(ip).or_insert_with(|| Record::new(now));
+        record.banned_until = Some(now + duration);
+        warn!("Abuse: banned {} for {}s (admin)", ip, duration.as_secs());
+    }
+
+    /// Lift a ban and forget the address's score and strikes; false if it
+    /// wasn't banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let removed = self.records.lock().unwrap().remove(&ip);
+        let was_banned = removed.is_some_and(|r| r.banned(Instant::now()));
+        if was_banned {
+            warn!("Abuse: unbanned {} (admin)", ip);
+        }
+        was_banned
+    }
+
+    /// Addresses banned right now, with the time left
+    pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+        let mut bans: Vec<_> = records
+            .iter()
+            .filter_map(|(ip, r)| Some((*ip, r.banned_until?.checked_duration_since(now)?)))
+            .filter(|(_, left)| !left.is_zero())
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// Drop records that have decayed to nothing, aren't banned and whose
+    /// last ban is long enough ago not to escalate the next one
+    fn sweep(&self, records: &mut HashMap<IpAddr, Record>, now: Instant) {
+        if records.len() < 1024 {
+            return;
+        }
+        let forget_after = Duration::from_secs(self.config.max_ban_secs);
+        records.retain(|_, r| {
+            r.decay(&self.config, now);
+            r.score >= 0.5 || r.banned_until.is_some_and(|until| until + forget_after > now)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> AbuseTracker {
+        AbuseTracker::new(AbuseConfig {
+            delay_after: 2,
+            delay_step_ms: 100,
+            max_delay_ms: 250,
+            ban_after: 5,
+            ban_secs: 60,
+            max_ban_secs: 100,
+            decay_secs: 3600,
+            exempt: vec!["192.0.2.9".parse().unwrap()],
+        })
+    }
+
+    #[test]
+    fn delay_then_ban() {
+        let abuse = tracker();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(abuse.penalty(ip), Penalty::None);
+
+        for _ in 0..3 {
+            assert!(!abuse.record_failure(ip));
+        }
+        let Penalty::Delay(delay) = abuse.penalty(ip) else { panic!("expected a delay") };
+        assert!(delay > Duration::from_millis(90) && delay <= Duration::from_millis(100));
+        abuse.record_failure(ip);
+        let Penalty::Delay(delay) = abuse.penalty(ip) else { panic!("expected a delay") };
+        assert!(delay > Duration::from_millis(190) && delay <= Duration::from_millis(200));
+
+        assert!(abuse.record_failure(ip));
+        assert_eq!(abuse.penalty(ip), Penalty::Banned);
+        let bans = abuse.bans();
+        assert_eq!(bans.len(), 1);
+        assert!(bans[0].1 > Duration::from_secs(59));
+
+        assert!(abuse.unban(ip));
+        assert_eq!(abuse.penalty(ip), Penalty::None);
+
+        // Exempt addresses are never penalised
+        let exempt = "192.0.2.9".parse().unwrap();
+        for _ in 0..10 {
+            assert!(!abuse.record_failure(exempt));
+        }
+        assert_eq!(abuse.penalty(exempt), Penalty::None);
+    }
+
+    #[test]
+    fn repeat_bans_escalate() {
+        let abuse = tracker();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..5 {
+            abuse.record_failure(ip);
+        }
+        // Expire the first ban without forgetting the strike; the second
+        // is twice as long, capped at max_ban_secs
+        abuse.records.lock().unwrap().get_mut(&ip).unwrap().banned_until = Some(Instant::now());
+        for _ in 0..5 {
+            abuse.record_failure(ip);
+        }
+        let bans = abuse.bans();
+        assert!(bans[0].1 > Duration::from_secs(99) && bans[0].1 <= Duration::from_secs(100));
+    }
+}
@@ -0,0 +1,144 @@
+//! HTTP admin listener (see `[admin]` in relay.example.toml)
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Method, Request, StatusCode};
+use tokio::net::TcpListener;
+
+use crate::http::{self, HttpResponse, respond};
+use crate::quota::SourceLimits;
+use crate::router::Router;
+
+pub struct Admin {
+    sources: Arc<SourceLimits>,
+    router: Arc<Router>,
+    token: Option<String>,
+    /// Default length of a ban set without `?secs=`
+    max_ban: Duration,
+}
+
+impl Admin {
+    pub fn new(sources: Arc<SourceLimits>, router: Arc<Router>, token: Option<String>, max_ban: Duration) -> Arc<Self> {
+        Arc::new(Self { sources, router, token, max_ban })
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        http::serve(listener, move |r| {
+            let response = self.handle(r);
+            async move { response }
+        })
+        .await
+    }
+
+    fn handle<B>(&self, r: Request<B>) -> HttpResponse {
+        if !self.authorized(&r) {
+            return respond(StatusCode::UNAUTHORIZED, "unauthorized\n");
+        }
+        let path = r.uri().path();
+        if path == "/bans" {
+            if r.method() != Method::GET {
+                return respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+            }
+            let mut body = String::new();
+            for (ip, left) in self.sources.abuse().bans() {
+                body.push_str(&format!("{} {}\n", ip, left.as_secs()));
+            }
+            return respond(StatusCode::OK, body);
+        }
+        if path == "/queues" {
+            if r.method() != Method::GET {
+                return respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+            }
+            let mut body = String::new();
+            for (id52, depth) in self.router.queue_depths() {
+                if let Ok(id52) = fastn_id52::PublicKey::from_bytes(&id52) {
+                    body.push_str(&format!("{} {}\n", id52, depth));
+                }
+            }
+            return respond(StatusCode::OK, body);
+        }
+
+        let Some(ip) = path.strip_prefix("/bans/") else {
+            return respond(StatusCode::NOT_FOUND, "not found\n");
+        };
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            return respond(StatusCode::BAD_REQUEST, "invalid address\n");
+        };
+        match *r.method() {
+            Method::PUT => {
+                let secs = r.uri().query().and_then(|q| q.split('&').find_map(|kv| kv.strip_prefix("secs=")));
+                let duration = match secs.map(str::parse::<u64>) {
+                    None => self.max_ban,
+                    Some(Ok(secs)) if secs > 0 => Duration::from_secs(secs),
+                    Some(_) => return respond(StatusCode::BAD_REQUEST, "invalid secs\n"),
+                };
+                self.sources.abuse().ban(ip, duration);
+                respond(StatusCode::OK, format!("{} {}\n", ip, duration.as_secs()))
+            }
+            Method::DELETE if self.sources.abuse().unban(ip) => respond(StatusCode::OK, "unbanned\n"),
+            Method::DELETE => respond(StatusCode::NOT_FOUND, "not banned\n"),
+            _ => respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n"),
+        }
+    }
+
+    fn authorized<B>(&self, r: &Request<B>) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let presented = r
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        presented.is_some_and(|p| constant_time_eq(p.as_bytes(), token.as_bytes()))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Abuse, Config, Quotas};
+
+    fn request(method: Method, uri: &str, token: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {token}"));
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn ban_list() {
+        let sources = SourceLimits::new(Quotas::default(), Abuse::default());
+        let router = Router::new(&Config::default(), Arc::new(fastn_id52::SecretKey::generate()));
+        let admin = Admin::new(sources.clone(), router, Some("secret".into()), Duration::from_secs(600));
+
+        assert_eq!(admin.handle(request(Method::GET, "/bans", None)).status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(admin.handle(request(Method::GET, "/bans", Some("wrong"))).status(), StatusCode::UNAUTHORIZED);
+
+        let put = |uri| admin.handle(request(Method::PUT, uri, Some("secret"))).status();
+        assert_eq!(put("/bans/192.0.2.1?secs=30"), StatusCode::OK);
+        assert_eq!(put("/bans/2001:db8::1"), StatusCode::OK);
+        assert_eq!(put("/bans/nope"), StatusCode::BAD_REQUEST);
+        assert_eq!(put("/bans/192.0.2.2?secs=x"), StatusCode::BAD_REQUEST);
+
+        let bans = sources.abuse().bans();
+        assert_eq!(bans.len(), 2);
+        assert!(bans[0].1 <= Duration::from_secs(30));
+        assert!(bans[1].1 > Duration::from_secs(590));
+
+        let delete = |uri| admin.handle(request(Method::DELETE, uri, Some("secret"))).status();
+        assert_eq!(delete("/bans/192.0.2.1"), StatusCode::OK);
+        assert_eq!(delete("/bans/192.0.2.1"), StatusCode::NOT_FOUND);
+        assert_eq!(sources.abuse().bans().len(), 1);
+
+        assert_eq!(admin.handle(request(Method::GET, "/queues", Some("secret"))).status(), StatusCode::OK);
+        assert_eq!(admin.handle(request(Method::PUT, "/queues", Some("secret"))).status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,173 @@
+//! Response cache for idempotent retries (protocol §6.2), bounded by entry
+//! count and total bytes
+//!
+//! A response can be fetched any number of times until its TTL runs out, so
+//! every retry of a SEND gets it, not just the first. When over budget the
+//! least recently used responses are evicted; expired ones are dropped when
+//! looked up or by `sweep`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use crate::config::Cache as CacheConfig;
+
+/// Preimage key plus bookkeeping, charged on top of the response itself
+const ENTRY_OVERHEAD: usize = 64;
+
+struct CachedResponse {
+    response: Vec<u8>,
+    expires_at: Instant,
+    /// Position in `ResponseCache::recency`
+    used: u64,
+}
+
+impl CachedResponse {
+    fn size(&self) -> usize {
+        self.response.len() + ENTRY_OVERHEAD
+    }
+}
+
+pub struct ResponseCache {
+    entries: HashMap<[u8; 32], CachedResponse>,
+    /// Preimages by last use, least recent first
+    recency: BTreeMap<u64, [u8; 32]>,
+    next_use: u64,
+    /// Sum of `CachedResponse::size` over `entries`
+    bytes: usize,
+    config: CacheConfig,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self { entries: HashMap::new(), recency: BTreeMap::new(), next_use: 0, bytes: 0, config }
+    }
+
+    /// Cache `response` under `preimage` for the configured TTL, replacing
+    /// any earlier one
+    ///
+    /// Least recently used responses are evicted to make room. A response
+    /// bigger than the whole byte budget is not cached.
+    pub fn insert(&mut self, preimage: [u8; 32], response: Vec<u8>) {
+        let used = self.next_use();
+        let entry = CachedResponse { response, expires_at: Instant::now() + self.config.ttl(), used };
+        if entry.size() > self.config.max_bytes {
+            return;
+        }
+        self.remove(&preimage);
+
+        while self.entries.len() >= self.config.max_entries || self.bytes + entry.size() > self.config.max_bytes {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+
+        self.bytes += entry.size();
+        self.recency.insert(used, preimage);
+        self.entries.insert(preimage, entry);
+    }
+
+    /// An unexpired response, counted as a use; it stays cached
+    pub fn get(&mut self, preimage: &[u8; 32]) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let used = self.next_use();
+        let entry = self.entries.get_mut(preimage)?;
+        if entry.expires_at <= now {
+            self.remove(preimage);
+            return None;
+        }
+        self.recency.remove(&entry.used);
+        entry.used = used;
+        self.recency.insert(used, *preimage);
+        Some(entry.response.clone())
+    }
+
+    /// Drop expired entries; returns how many
+    pub fn sweep(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<_> = self.entries.iter().filter(|(_, v)| v.expires_at <= now).map(|(k, _)| *k).collect();
+        for preimage in &expired {
+            self.remove(preimage);
+        }
+        expired.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.next_use += 1;
+        self.next_use
+    }
+
+    fn remove(&mut self, preimage: &[u8; 32]) -> Option<CachedResponse> {
+        let entry = self.entries.remove(preimage)?;
+        self.recency.remove(&entry.used);
+        self.bytes -= entry.size();
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_budget() {
+        let mut cache = ResponseCache::new(CacheConfig {
+            ttl_secs: 60,
+            max_entries: 10,
+            max_bytes: 3 * (100 + ENTRY_OVERHEAD),
+        });
+        for i in 0..4 {
+            cache.insert([i; 32], vec![i; 100]);
+        }
+        // The least recently used made room for the fourth
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes(), 3 * (100 + ENTRY_OVERHEAD));
+        assert_eq!(cache.get(&[0; 32]), None);
+        assert_eq!(cache.get(&[3; 32]), Some(vec![3; 100]));
+
+        // Too big to ever fit
+        cache.insert([9; 32], vec![0; 1000]);
+        assert_eq!(cache.get(&[9; 32]), None);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = ResponseCache::new(CacheConfig { ttl_secs: 60, max_entries: 3, max_bytes: 1 << 20 });
+        for i in 0..3 {
+            cache.insert([i; 32], vec![i]);
+        }
+        // Hits don't remove, and keep an entry from being next out
+        for _ in 0..2 {
+            assert_eq!(cache.get(&[0; 32]), Some(vec![0]));
+        }
+        cache.insert([3; 32], vec![3]);
+        assert_eq!(cache.get(&[1; 32]), None);
+        assert_eq!(cache.get(&[0; 32]), Some(vec![0]));
+        assert_eq!(cache.get(&[2; 32]), Some(vec![2]));
+
+        // Replacing a response doesn't count it twice
+        cache.insert([2; 32], vec![2; 10]);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes(), 12 + 3 * ENTRY_OVERHEAD);
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let mut cache = ResponseCache::new(CacheConfig { ttl_secs: 0, max_entries: 10, max_bytes: 1 << 20 });
+        cache.insert([1; 32], vec![1]);
+        cache.insert([2; 32], vec![2]);
+        assert_eq!(cache.get(&[1; 32]), None);
+        assert_eq!(cache.sweep(), 1);
+        assert_eq!((cache.len(), cache.bytes()), (0, 0));
+        assert!(cache.recency.is_empty());
+    }
+}
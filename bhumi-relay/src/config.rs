@@ -0,0 +1,509 @@
+//! Relay configuration: TOML file plus command line
+//!
+//! Every setting has a default, so a home relay needs no file at all. The
+//! command line (and its `BHUMI_RELAY_*` environment variables) overrides
+//! the file for listeners, peers and logging; limits, quotas,
+//! timeouts and cache sizing live in the file. See `relay.example.toml`.
+
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bhumi_proto::{RelayAddr, RelayPin};
+use bhumi_proto::MAX_FRAME_SIZE;
+
+use crate::log::{LogFormat, LogLevel, LogRedact};
+
+/// Plaintext listener used when nothing else is configured
+const DEFAULT_PLAIN_ADDR: &str = "0.0.0.0:8443";
+
+/// TLS listener used when a certificate is configured without an address
+const DEFAULT_TLS_ADDR: &str = "0.0.0.0:443";
+
+/// Smallest `max_payload_size` that still fits an I_AM with a useful
+/// number of commits
+const MIN_PAYLOAD_SIZE: u32 = 4 * 1024;
+
+#[derive(clap::Parser, Debug, Default)]
+#[command(name = "bhumi-relay")]
+#[command(about = "Bhumi relay server")]
+pub struct Cli {
+    /// TOML config file (default: $BHUMI_HOME/relay.toml, if it exists)
+    #[arg(short, long, env = "BHUMI_RELAY_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Plaintext listen address (development only)
+    #[arg(long, env = "BHUMI_RELAY_ADDR")]
+    pub listen: Option<String>,
+    /// TLS listen address
+    #[arg(long, env = "BHUMI_RELAY_TLS_ADDR")]
+    pub tls_listen: Option<String>,
+    /// TLS certificate chain (PEM); without one the relay identity certificate is used
+    #[arg(long, env = "BHUMI_RELAY_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[arg(long, env = "BHUMI_RELAY_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Relay to gossip PRESENCE with, as tls://ADDR?id52=<relay id52>
+    /// (repeatable, or comma-separated)
+    #[arg(long = "peer", value_name = "ADDR", env = "BHUMI_RELAY_PEERS", value_delimiter = ',')]
+    pub peers: Vec<String>,
+    /// error, warn, info or debug
+    #[arg(long, env = "BHUMI_RELAY_LOG")]
+    pub log_level: Option<LogLevel>,
+    /// pretty or json
+    #[arg(long, env = "BHUMI_RELAY_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// How id52s appear in the log: off, truncate or hash
+    #[arg(long, env = "BHUMI_RELAY_LOG_REDACT")]
+    pub log_redact: Option<LogRedact>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub log_redact: LogRedact,
+    /// Relays to gossip PRESENCE with and forward SENDs to, each a
+    /// `tls://host:port?id52=<relay id52>` address
+    pub peers: Vec<String>,
+    pub listen: Listen,
+    pub limits: Limits,
+    pub quotas: Quotas,
+    pub abuse: Abuse,
+    pub timeouts: Timeouts,
+    pub cache: Cache,
+    pub admin: Admin,
+    pub metrics: Metrics,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listen {
+    /// Plaintext address (default 0.0.0.0:8443, only when TLS is off)
+    pub plain: Option<String>,
+    /// TLS address (default 0.0.0.0:443 when a certificate is set)
+    pub tls: Option<String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Largest frame payload accepted or sent, advertised in HELLO
+    pub max_payload_size: u32,
+    /// Deliveries queued per connected device but not yet written to it;
+    /// SENDs finding the queue full get SEND_ERR_BUSY. Below
+    /// `quotas.max_in_flight_per_recipient` so a device that stops reading
+    /// is caught before one that is slow to ACK
+    pub delivery_queue: usize,
+    /// PRESENCE queued per peer link; beyond this gossip is dropped
+    pub gossip_queue: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_payload_size: 64 * 1024,
+            delivery_queue: 8,
+            gossip_queue: 256,
+        }
+    }
+}
+
+/// Per-device and per-source-address quotas
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quotas {
+    /// Commits one id52 may hold; an I_AM or UPDATE_COMMITS going over
+    /// closes the session
+    pub max_commits_per_device: usize,
+    /// Deliveries awaiting ACK per recipient; further SENDs get
+    /// SEND_ERR_TOO_MANY_IN_FLIGHT
+    pub max_in_flight_per_recipient: usize,
+    /// SENDs one session may have awaiting their SEND_RESULT; further SENDs
+    /// get SEND_ERR_TOO_MANY_IN_FLIGHT
+    pub max_sends_per_session: usize,
+    /// Concurrent connections per source IP; further ones are closed on accept
+    pub max_connections_per_ip: usize,
+    /// Sustained SENDs per second per source IP; beyond the burst SENDs get
+    /// SEND_ERR_RATE_LIMITED
+    pub sends_per_sec: u32,
+    pub send_burst: u32,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self {
+            max_commits_per_device: 1024,
+            max_in_flight_per_recipient: 16,
+            max_sends_per_session: 64,
+            max_connections_per_ip: 64,
+            sends_per_sec: 20,
+            send_burst: 40,
+        }
+    }
+}
+
+/// Penalties for source addresses whose SENDs keep failing with an invalid
+/// preimage or an unconnected recipient (see `abuse`)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Abuse {
+    /// Score (failures, halving every `decay_secs`) above which SENDs are delayed
+    pub delay_after: u32,
+    /// Delay per point above `delay_after`
+    pub delay_step_ms: u64,
+    pub max_delay_ms: u64,
+    /// Score at which the address is banned
+    pub ban_after: u32,
+    /// First ban; each further ban is twice as long
+    pub ban_secs: u64,
+    pub max_ban_secs: u64,
+    pub decay_secs: u64,
+    /// Addresses never penalised (e.g. peer relays forwarding SENDs)
+    pub exempt: Vec<IpAddr>,
+}
+
+impl Default for Abuse {
+    fn default() -> Self {
+        Self {
+            delay_after: 10,
+            delay_step_ms: 100,
+            max_delay_ms: 5000,
+            ban_after: 50,
+            ban_secs: 60,
+            max_ban_secs: 24 * 60 * 60,
+            decay_secs: 60,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// HTTP admin listener (off unless `listen` is set)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    /// Keep this on a loopback or otherwise private address
+    pub listen: Option<String>,
+    /// If set, requests must carry `Authorization: Bearer <token>`
+    pub token: Option<String>,
+}
+
+/// Prometheus `/metrics` and `/healthz` listener (off unless `listen` is set)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Metrics {
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// How long a SEND waits for the recipient's ACK, unless it sets a deadline
+    pub delivery_secs: u64,
+    /// Shortest and longest deadline a SEND may set; others are clamped
+    pub min_delivery_ms: u64,
+    pub max_delivery_secs: u64,
+    /// Sessions that send nothing (not even KEEPALIVE) for this long are dropped
+    pub idle_secs: u64,
+    /// Whole forwarded exchange, including the other relay's delivery timeout
+    pub forward_secs: u64,
+    /// Give up on clients that don't finish the TLS handshake in time
+    pub tls_handshake_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            delivery_secs: 30,
+            min_delivery_ms: 100,
+            max_delivery_secs: 120,
+            idle_secs: 90,
+            forward_secs: 35,
+            tls_handshake_secs: 10,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn delivery(&self) -> Duration {
+        Duration::from_secs(self.delivery_secs)
+    }
+
+    /// How long a SEND with `deadline_ms` waits (0: no deadline, `delivery`)
+    pub fn delivery_for(&self, deadline_ms: u32) -> Duration {
+        if deadline_ms == 0 {
+            return self.delivery();
+        }
+        let min = Duration::from_millis(self.min_delivery_ms);
+        let max = Duration::from_secs(self.max_delivery_secs);
+        Duration::from_millis(deadline_ms.into()).clamp(min, max)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle_secs)
+    }
+
+    pub fn forward(&self) -> Duration {
+        Duration::from_secs(self.forward_secs)
+    }
+
+    /// Whole forwarded exchange for a SEND that waits `delivery` for the
+    /// recipient: the same margin over it as `forward_secs` has over `delivery_secs`
+    pub fn forward_for(&self, delivery: Duration) -> Duration {
+        delivery + (self.forward() - self.delivery())
+    }
+
+    pub fn tls_handshake(&self) -> Duration {
+        Duration::from_secs(self.tls_handshake_secs)
+    }
+}
+
+/// Response cache for idempotent retries (protocol §6.3)
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    pub ttl_secs: u64,
+    /// Most responses held at once; the least recently used go first
+    pub max_entries: usize,
+    /// Total size of cached responses
+    pub max_bytes: usize,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self { ttl_secs: 300, max_entries: 100_000, max_bytes: 64 * 1024 * 1024 }
+    }
+}
+
+impl Cache {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+impl Config {
+    /// Read the config file named on the command line (or the default one
+    /// in `bhumi_home`), apply the command line on top and validate
+    pub fn load(cli: Cli, bhumi_home: &Path) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => Self::read(path)?,
+            None => {
+                let path = bhumi_home.join("relay.toml");
+                if path.exists() { Self::read(&path)? } else { Self::default() }
+            }
+        };
+        config.apply(cli);
+        config.resolve();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn apply(&mut self, cli: Cli) {
+        if cli.listen.is_some() {
+            self.listen.plain = cli.listen;
+        }
+        if cli.tls_listen.is_some() {
+            self.listen.tls = cli.tls_listen;
+        }
+        if cli.tls_cert.is_some() {
+            self.listen.tls_cert = cli.tls_cert;
+            self.listen.tls_key = cli.tls_key;
+        }
+        let peers: Vec<String> = cli.peers.iter().map(|p| p.trim()).filter(|p| !p.is_empty()).map(String::from).collect();
+        if !peers.is_empty() {
+            self.peers = peers;
+        }
+        if let Some(level) = cli.log_level {
+            self.log_level = level;
+        }
+        if let Some(format) = cli.log_format {
+            self.log_format = format;
+        }
+        if let Some(redact) = cli.log_redact {
+            self.log_redact = redact;
+        }
+    }
+
+    /// Fill in default listeners
+    fn resolve(&mut self) {
+        let listen = &mut self.listen;
+        if listen.tls.is_none() && listen.tls_cert.is_some() {
+            listen.tls = Some(DEFAULT_TLS_ADDR.to_string());
+        }
+        // Plaintext is on by default only when TLS isn't configured
+        if listen.plain.is_none() && listen.tls.is_none() {
+            listen.plain = Some(DEFAULT_PLAIN_ADDR.to_string());
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.listen.tls_cert.is_some() != self.listen.tls_key.is_some() {
+            return Err("listen.tls_cert and listen.tls_key must be set together".into());
+        }
+        for peer in &self.peers {
+            // Forwarded SENDs carry preimages, so peers are TLS only, pinned
+            // to the relay identity PRESENCE names them by
+            let addr = RelayAddr::parse(peer).map_err(|e| format!("peer {:?}: {}", peer, e))?;
+            if !matches!(addr.tls, Some(RelayPin::Id52(_))) {
+                return Err(format!("peer {:?} must be tls://host:port?id52=<relay id52>", peer));
+            }
+        }
+
+        let limits = &self.limits;
+        if !(MIN_PAYLOAD_SIZE as usize..=MAX_FRAME_SIZE).contains(&(limits.max_payload_size as usize)) {
+            return Err(format!(
+                "limits.max_payload_size must be between {} and {}",
+                MIN_PAYLOAD_SIZE, MAX_FRAME_SIZE
+            ));
+        }
+        if limits.delivery_queue == 0 || limits.gossip_queue == 0 {
+            return Err("limits.delivery_queue and limits.gossip_queue must be at least 1".into());
+        }
+
+        let quotas = &self.quotas;
+        for (name, value) in [
+            ("max_commits_per_device", quotas.max_commits_per_device),
+            ("max_in_flight_per_recipient", quotas.max_in_flight_per_recipient),
+            ("max_sends_per_session", quotas.max_sends_per_session),
+            ("max_connections_per_ip", quotas.max_connections_per_ip),
+            ("sends_per_sec", quotas.sends_per_sec as usize),
+        ] {
+            if value == 0 {
+                return Err(format!("quotas.{} must be at least 1", name));
+            }
+        }
+        if quotas.send_burst < quotas.sends_per_sec {
+            return Err("quotas.send_burst must be at least quotas.sends_per_sec".into());
+        }
+
+        let abuse = &self.abuse;
+        if abuse.ban_after <= abuse.delay_after {
+            return Err("abuse.ban_after must be greater than abuse.delay_after".into());
+        }
+        if abuse.decay_secs == 0 || abuse.ban_secs == 0 || abuse.max_ban_secs < abuse.ban_secs {
+            return Err("abuse.decay_secs and abuse.ban_secs must be at least 1, and max_ban_secs at least ban_secs".into());
+        }
+        if self.admin.token.as_deref().is_some_and(str::is_empty) {
+            return Err("admin.token must not be empty".into());
+        }
+
+        let timeouts = &self.timeouts;
+        for (name, secs) in [
+            ("delivery_secs", timeouts.delivery_secs),
+            ("idle_secs", timeouts.idle_secs),
+            ("forward_secs", timeouts.forward_secs),
+            ("tls_handshake_secs", timeouts.tls_handshake_secs),
+        ] {
+            if secs == 0 {
+                return Err(format!("timeouts.{} must be at least 1", name));
+            }
+        }
+        // A forwarded SEND waits for the other relay's delivery timeout
+        if timeouts.forward_secs <= timeouts.delivery_secs {
+            return Err("timeouts.forward_secs must be longer than timeouts.delivery_secs".into());
+        }
+        if timeouts.min_delivery_ms == 0
+            || timeouts.min_delivery_ms > timeouts.delivery_secs * 1000
+            || timeouts.max_delivery_secs < timeouts.delivery_secs
+        {
+            return Err("timeouts.min_delivery_ms must be between 1 and delivery_secs, and max_delivery_secs at least delivery_secs".into());
+        }
+
+        if self.cache.ttl_secs == 0 || self.cache.max_entries == 0 {
+            return Err("cache.ttl_secs and cache.max_entries must be at least 1".into());
+        }
+        // Room for at least one response of the largest payload
+        if self.cache.max_bytes < self.limits.max_payload_size as usize {
+            return Err("cache.max_bytes must be at least limits.max_payload_size".into());
+        }
+        Ok(())
+    }
+
+    /// Peer relays, parsed (each pinned to a relay id52 once validated)
+    pub fn peer_addrs(&self) -> Vec<RelayAddr> {
+        self.peers.iter().filter_map(|peer| RelayAddr::parse(peer).ok()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let mut config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.resolve();
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn defaults_and_example() {
+        let config = parse("").unwrap();
+        assert_eq!(config.listen.plain.as_deref(), Some(DEFAULT_PLAIN_ADDR));
+        assert_eq!(config.limits, Limits::default());
+
+        let example = parse(include_str!("../relay.example.toml")).unwrap();
+        assert_eq!(example.listen.plain, None);
+        assert_eq!(example.listen.tls.as_deref(), Some("0.0.0.0:443"));
+        assert_eq!(example.log_level, LogLevel::Info);
+        assert_eq!(example.log_redact, LogRedact::Hash);
+    }
+
+    #[test]
+    fn send_deadlines_are_clamped() {
+        let timeouts = Timeouts::default();
+        assert_eq!(timeouts.delivery_for(0), Duration::from_secs(30));
+        assert_eq!(timeouts.delivery_for(1), Duration::from_millis(100));
+        assert_eq!(timeouts.delivery_for(2500), Duration::from_millis(2500));
+        assert_eq!(timeouts.delivery_for(u32::MAX), Duration::from_secs(120));
+        assert_eq!(timeouts.forward_for(Duration::from_secs(1)), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn cli_overrides_file() {
+        let mut config = parse("log_level = \"debug\"\n[listen]\nplain = \"0.0.0.0:1\"").unwrap();
+        config.apply(Cli {
+            listen: Some("127.0.0.1:2".into()),
+            peers: vec!["a:1".into(), " ".into()],
+            log_level: Some(LogLevel::Warn),
+            log_format: Some(LogFormat::Json),
+            ..Default::default()
+        });
+        assert_eq!(config.listen.plain.as_deref(), Some("127.0.0.1:2"));
+        assert_eq!(config.peers, vec!["a:1".to_string()]);
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert!(parse("[limits]\nmax_payload_size = 100").is_err());
+        assert!(parse("[limits]\ndelivery_queue = 0").is_err());
+        assert!(parse("[timeouts]\nidle_secs = 0").is_err());
+        assert!(parse("[timeouts]\ndelivery_secs = 60").is_err());
+        assert!(parse("[timeouts]\nmax_delivery_secs = 10").is_err());
+        assert!(parse("[timeouts]\nmin_delivery_ms = 0").is_err());
+        assert!(parse("[cache]\nmax_entries = 0").is_err());
+        assert!(parse("[cache]\nmax_bytes = 1024").is_err());
+        assert!(parse("[quotas]\nmax_connections_per_ip = 0").is_err());
+        assert!(parse("[quotas]\nsends_per_sec = 50").is_err());
+        assert!(parse("[abuse]\nban_after = 5").is_err());
+        assert!(parse("[abuse]\nexempt = [\"relay-b\"]").is_err());
+        assert!(parse("[listen]\ntls_cert = \"cert.pem\"").is_err());
+        assert!(parse("[listen]\nplian = \"0.0.0.0:1\"").is_err());
+        assert!(parse("log_level = \"loud\"").is_err());
+        assert!(parse("log_redact = \"some\"").is_err());
+        // Peers must be TLS, pinned to a relay id52
+        assert!(parse("peers = [\"relay-b:8443\"]").is_err());
+        assert!(parse(&format!("peers = [\"relay-b:8443?id52={}\"]", fastn_id52::SecretKey::generate().public_key())).is_err());
+    }
+}
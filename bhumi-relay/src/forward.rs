@@ -0,0 +1,61 @@
+//! Forward: hand a SEND to the relay the recipient's PRESENCE names
+//!
+//! The forwarding relay acts like an anonymous sender on a short-lived
+//! connection, announced with RELAY_PEER so the other relay delivers
+//! locally or fails instead of forwarding again (one hop at most). Only
+//! configured peers are dialled, over the same pinned TLS as gossip links.
+
+use futures::SinkExt;
+use std::time::Duration;
+use tracing::debug;
+
+use bhumi_proto::RelayAddr;
+use bhumi_proto::{RelayMessage, Send as SendMsg, SendResult};
+use bhumi_proto::{SEND_ERR_DISCONNECTED, SEND_ERR_NOT_CONNECTED, SEND_ERR_TIMEOUT};
+use fastn_id52::PublicKey;
+
+use crate::gossip::{connect_peer, next_message, unexpected};
+use crate::router::Router;
+
+/// Forward `send` to peer relay `relay_id52` at `addr` and return its
+/// SEND_RESULT
+///
+/// `timeout` covers connection setup and, separately, the exchange (which
+/// includes the remote relay's own delivery timeout).
+///
+/// If the relay can't be reached (or isn't who it should be) the recipient
+/// is reported as not connected. Once connected the outcome of a failure is
+/// unknown, so the sender is told the delivery was interrupted and can
+/// retry with the same preimage.
+pub async fn forward_send(relay_id52: PublicKey, addr: &RelayAddr, router: &Router, send: SendMsg, timeout: Duration) -> SendResult {
+    let (mut reader, mut writer) = match tokio::time::timeout(timeout, connect_peer(relay_id52, addr, router)).await {
+        Ok(Ok(link)) => link,
+        Ok(Err(e)) => {
+            debug!("forward to {} failed: {}", relay_id52, e);
+            return SendResult::error(SEND_ERR_NOT_CONNECTED);
+        }
+        Err(_) => return SendResult::error(SEND_ERR_NOT_CONNECTED),
+    };
+
+    let exchange = async {
+        writer.send(RelayMessage::from(send)).await?;
+
+        loop {
+            match next_message(&mut reader).await? {
+                RelayMessage::SendResult(result) => return Ok(result),
+                // Gossip also flows on RELAY_PEER sessions
+                RelayMessage::Presence(_) | RelayMessage::Keepalive => {}
+                other => return Err(unexpected("SEND_RESULT", &other)),
+            }
+        }
+    };
+
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
+            debug!("forward to {} failed: {}", relay_id52, e);
+            SendResult::error(SEND_ERR_DISCONNECTED)
+        }
+        Err(_) => SendResult::error(SEND_ERR_TIMEOUT),
+    }
+}
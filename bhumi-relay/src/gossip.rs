@@ -0,0 +1,122 @@
+//! Gossip: outbound relay-to-relay links for PRESENCE (protocol §7.2)
+//!
+//! A link is an ordinary relay session on which the connecting relay sends
+//! RELAY_PEER instead of I_AM. Both ends then push PRESENCE they learn to
+//! each other; duplicates and stale entries are dropped by the router.
+//!
+//! Peer relays are only dialled over TLS with an id52 pin (checked by
+//! config), with the same client devices use, and must sign HELLO with
+//! that id52.
+
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, warn};
+
+use bhumi_proto::{RelayAddr, Transport};
+use bhumi_proto::{BhumiCodec, RelayMessage, RelayPeer, SignedPresence, CAP_RELAY_PEERING};
+use fastn_id52::PublicKey;
+
+use crate::router::Router;
+
+/// Wait between attempts to (re)connect to a peer relay
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Keep the link alive through the peer's idle timeout
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keep a gossip link to peer relay `relay_id52` at `addr` up for as long
+/// as the relay runs
+pub async fn maintain_link(relay_id52: PublicKey, addr: RelayAddr, router: Arc<Router>) {
+    loop {
+        if let Err(e) = run_link(relay_id52, &addr, &router).await {
+            warn!("Peer relay {}: {}", relay_id52, e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Read half of a connection to a peer relay
+pub type PeerReader = FramedRead<ReadHalf<Box<dyn Transport>>, BhumiCodec>;
+/// Write half of a connection to a peer relay
+pub type PeerWriter = FramedWrite<WriteHalf<Box<dyn Transport>>, BhumiCodec>;
+
+/// Connect to peer relay `relay_id52` at `addr`, check its HELLO is signed
+/// by that id52 and announce ourselves with a RELAY_PEER signed over its nonce
+pub async fn connect_peer(relay_id52: PublicKey, addr: &RelayAddr, router: &Router) -> std::io::Result<(PeerReader, PeerWriter)> {
+    let (reader, writer) = tokio::io::split(addr.connect().await?);
+    let mut reader = FramedRead::new(reader, BhumiCodec::new());
+    let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+
+    let hello = match next_message(&mut reader).await? {
+        RelayMessage::Hello(hello) => hello,
+        other => return Err(unexpected("HELLO", &other)),
+    };
+    if hello.verified_relay_id52() != Some(relay_id52) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("relay HELLO is not signed by {}", relay_id52),
+        ));
+    }
+    if !hello.supports(CAP_RELAY_PEERING) {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "relay does not accept peer links"));
+    }
+    let max_payload = hello.max_payload_size as usize;
+    reader.decoder_mut().set_max_payload(max_payload);
+    writer.encoder_mut().set_max_payload(max_payload);
+
+    writer.send(RelayMessage::from(RelayPeer::sign(hello.nonce, router.secret_key()))).await?;
+    Ok((reader, writer))
+}
+
+pub async fn next_message(reader: &mut PeerReader) -> std::io::Result<RelayMessage> {
+    let frame = reader.next().await.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))??;
+    Ok(frame.decode()?)
+}
+
+pub fn unexpected(expected: &str, got: &RelayMessage) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("expected {}, got 0x{:04x}", expected, got.msg_type()),
+    )
+}
+
+async fn run_link(relay_id52: PublicKey, addr: &RelayAddr, router: &Arc<Router>) -> std::io::Result<()> {
+    let (mut reader, mut writer) = connect_peer(relay_id52, addr, router).await?;
+
+    let (tx, mut rx) = mpsc::channel::<SignedPresence>(router.limits().gossip_queue);
+    let link = router.register_peer(relay_id52, tx);
+    let result = async {
+        let mut ping = tokio::time::interval(KEEPALIVE_INTERVAL);
+        loop {
+            tokio::select! {
+                frame = reader.next() => {
+                    let Some(frame) = frame else {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    };
+                    match frame?.decode()? {
+                        RelayMessage::Presence(signed) => {
+                            if let Err(e) = router.store_presence(signed, Some(link)) {
+                                debug!("Gossip from {}: {}, ignored", relay_id52, e);
+                            }
+                        }
+                        RelayMessage::Keepalive => {}
+                        other => debug!("Unexpected message type from peer: 0x{:04x}", other.msg_type()),
+                    }
+                }
+                Some(presence) = rx.recv() => {
+                    writer.send(RelayMessage::from(presence)).await?;
+                }
+                _ = ping.tick() => {
+                    writer.send(RelayMessage::Keepalive).await?;
+                }
+            }
+        }
+    }
+    .await;
+    router.unregister_peer(link);
+    result
+}
@@ -0,0 +1,42 @@
+//! Minimal HTTP/1.1 and HTTP/2 serving for the admin and metrics listeners
+
+use std::future::Future;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::net::TcpListener;
+use tracing::debug;
+
+pub type HttpResponse = Response<Full<Bytes>>;
+
+/// Serve every connection on `listener` with `handler`; only returns if
+/// accepting fails
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F) -> std::io::Result<()>
+where
+    F: Fn(Request<Incoming>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = HttpResponse> + Send + 'static,
+{
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |r| {
+                let response = handler(r);
+                async move { Ok::<_, std::convert::Infallible>(response.await) }
+            });
+            let builder = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+            if let Err(e) = builder.serve_connection(TokioIo::new(stream), service).await {
+                debug!("HTTP connection from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+pub fn respond(status: StatusCode, body: impl Into<Bytes>) -> HttpResponse {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,46 @@
+//! Relay identity: the relay's own key, stored in `relay.secret`
+
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const KEY_FILE: &str = "relay.secret";
+
+/// Generate and store a new relay key; fails if one already exists
+pub fn create_key(home: &Path) -> std::io::Result<fastn_id52::SecretKey> {
+    let key = fastn_id52::SecretKey::generate();
+    let path = home.join(KEY_FILE);
+
+    std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, key.to_string().as_bytes()))?;
+
+    info!("Created relay key at {}", path.display());
+    Ok(key)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadKeyError {
+    #[error("secret key file not found: {0}")]
+    NotFound(PathBuf),
+    #[error("failed to read secret key file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid secret key format: {0}")]
+    Parse(String),
+}
+
+pub fn read_key(home: &Path) -> Result<fastn_id52::SecretKey, ReadKeyError> {
+    let path = home.join(KEY_FILE);
+
+    if !path.exists() {
+        return Err(ReadKeyError::NotFound(path));
+    }
+
+    let content = std::fs::read_to_string(&path)?;
+    let content = content.trim();
+
+    content
+        .parse::<fastn_id52::SecretKey>()
+        .map_err(|e| ReadKeyError::Parse(e.to_string()))
+}
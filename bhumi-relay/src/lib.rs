@@ -0,0 +1,19 @@
+//! The relay behind the `bhumi-relay` binary, as a library so benches can
+//! drive the router directly
+
+mod abuse;
+mod admin;
+mod cache;
+pub mod config;
+mod forward;
+mod gossip;
+mod http;
+pub mod id52;
+pub mod log;
+mod metrics;
+mod quota;
+pub mod router;
+pub mod server;
+mod session;
+mod shard;
+mod tls;
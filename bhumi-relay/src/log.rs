@@ -0,0 +1,89 @@
+//! Logging: `tracing` events to the console, pretty or one JSON object per line
+//!
+//! `info`/`debug` go to stdout, `warn`/`error` to stderr. Connections and
+//! registrations are `info`; per-frame detail is `debug`. Every event inside
+//! a connection carries its `session` span (peer address, then id52 once
+//! known), and deliveries a `msg` span with the msg_id.
+//!
+//! id52s are only ever logged through `bhumi_proto::redact::Id52`, so the
+//! redaction mode covers all of them; preimages are never logged.
+
+use std::io::IsTerminal;
+
+use bhumi_proto::redact::{self, Redaction};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+/// How much the relay logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[derive(serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Pretty,
+    /// One JSON object per event, for log collectors
+    Json,
+}
+
+/// How id52s appear in the log (see `bhumi_proto::redact`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRedact {
+    /// Full id52s
+    Off,
+    /// First 8 characters
+    Truncate,
+    /// Hash, keyed per process: lines for one device can be followed
+    /// within a run but not matched to an id52
+    #[default]
+    Hash,
+}
+
+impl From<LogRedact> for Redaction {
+    fn from(redact: LogRedact) -> Self {
+        match redact {
+            LogRedact::Off => Redaction::Off,
+            LogRedact::Truncate => Redaction::Truncate,
+            LogRedact::Hash => Redaction::Hash,
+        }
+    }
+}
+
+/// Install the global subscriber; call once, before anything logs
+pub fn init(level: LogLevel, format: LogFormat, redact: LogRedact) {
+    redact::set_redaction(redact.into());
+    let writer = std::io::stderr.with_max_level(tracing::Level::WARN).or_else(std::io::stdout);
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::from(level))
+        .with_writer(writer)
+        .with_ansi(std::io::stdout().is_terminal())
+        .with_target(false);
+    match format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).with_span_list(true).init(),
+    }
+}
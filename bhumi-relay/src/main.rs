@@ -1,11 +1,48 @@
-mod router;
-mod server;
-mod session;
+use std::path::PathBuf;
 
-use server::Server;
+use bhumi_relay::config::{Cli, Config};
+use bhumi_relay::server::Server;
+use bhumi_relay::{id52, log};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let server = Server::bind("0.0.0.0:8443").await?;
+    let cli: Cli = clap::Parser::parse();
+    let bhumi_home = std::env::var("BHUMI_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| dirs::home_dir().expect("Could not determine home directory").join(".bhumi"));
+    std::fs::create_dir_all(&bhumi_home)?;
+
+    let config = match Config::load(cli, &bhumi_home) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    log::init(config.log_level, config.log_format, config.log_redact);
+
+    let secret_key = match id52::read_key(&bhumi_home) {
+        Ok(key) => key,
+        Err(id52::ReadKeyError::NotFound(_)) => id52::create_key(&bhumi_home)?,
+        Err(e) => return Err(e.into()),
+    };
+
+    let listen = &config.listen;
+    let mut server = Server::new(&config, secret_key);
+    match (&listen.tls, &listen.tls_cert, &listen.tls_key) {
+        (Some(addr), Some(cert), Some(key)) => server = server.listen_tls(addr, cert, key).await?,
+        (Some(addr), _, _) => server = server.listen_tls_identity(addr).await?,
+        _ => {}
+    }
+    if let Some(addr) = &listen.plain {
+        server = server.listen_plain(addr).await?;
+    }
+    if let Some(addr) = &config.admin.listen {
+        let max_ban = std::time::Duration::from_secs(config.abuse.max_ban_secs);
+        server = server.listen_admin(addr, config.admin.token.clone(), max_ban).await?;
+    }
+    if let Some(addr) = &config.metrics.listen {
+        server = server.listen_metrics(addr).await?;
+    }
     server.run().await
 }
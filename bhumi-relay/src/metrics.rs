@@ -0,0 +1,236 @@
+//! Relay metrics, served in the Prometheus text format on `/metrics` with a
+//! `/healthz` liveness check (see `[metrics]` in relay.example.toml)
+//!
+//! Counters are bumped by the router and sessions; gauges (devices, commits,
+//! in-flight deliveries, response cache, delivery queues) are read from the
+//! router when scraped. Delivery queues are summed over all devices; the
+//! admin listener lists them per device.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use hyper::{Method, Request, StatusCode};
+use tokio::net::TcpListener;
+
+use bhumi_proto::{SEND_ERR_DISCONNECTED, SEND_ERR_INVALID_PREIMAGE, SEND_ERR_NOT_CONNECTED, SEND_ERR_NOT_ENCRYPTED};
+use bhumi_proto::{SEND_ERR_RATE_LIMITED, SEND_ERR_TIMEOUT, SEND_ERR_TOO_MANY_IN_FLIGHT, SEND_OK};
+use bhumi_proto::{SEND_ERR_BUSY, SEND_ERR_REFUSED};
+
+use crate::http::{self, HttpResponse, respond};
+use crate::router::Router;
+
+/// Upper bounds of the delivery latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Label for each SEND status the relay produces; always exported, even at 0
+const STATUSES: [(u8, &str); 10] = [
+    (SEND_OK, "ok"),
+    (SEND_ERR_NOT_CONNECTED, "not_connected"),
+    (SEND_ERR_INVALID_PREIMAGE, "invalid_preimage"),
+    (SEND_ERR_TIMEOUT, "timeout"),
+    (SEND_ERR_DISCONNECTED, "disconnected"),
+    (SEND_ERR_NOT_ENCRYPTED, "not_encrypted"),
+    (SEND_ERR_RATE_LIMITED, "rate_limited"),
+    (SEND_ERR_TOO_MANY_IN_FLIGHT, "too_many_in_flight"),
+    (SEND_ERR_BUSY, "busy"),
+    (SEND_ERR_REFUSED, "refused"),
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Per bucket (not cumulative), plus one for +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|le| secs <= *le).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters shared by the router and all sessions
+pub struct Metrics {
+    /// SEND_RESULTs by status byte
+    sends: [AtomicU64; 256],
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// SEND payloads and responses of successful SENDs
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+    /// Time from queueing a DELIVER to its ACK
+    delivery_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            sends: std::array::from_fn(|_| AtomicU64::new(0)),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            request_bytes: AtomicU64::new(0),
+            response_bytes: AtomicU64::new(0),
+            delivery_latency: Histogram::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a SEND_RESULT, and the bytes relayed if it succeeded
+    pub fn send(&self, status: u8, request_bytes: usize, response_bytes: usize) {
+        self.sends[status as usize].fetch_add(1, Ordering::Relaxed);
+        if status == SEND_OK {
+            self.request_bytes.fetch_add(request_bytes as u64, Ordering::Relaxed);
+            self.response_bytes.fetch_add(response_bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let counter = if hit { &self.cache_hits } else { &self.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn delivered(&self, latency: Duration) {
+        self.delivery_latency.observe(latency);
+    }
+
+    /// Prometheus text exposition of the counters plus the router's gauges
+    pub fn render(&self, router: &Router) -> String {
+        let gauges = router.gauges();
+        let mut out = String::new();
+
+        gauge(&mut out, "bhumi_relay_connected_devices", "Devices registered with I_AM", gauges.devices);
+        gauge(&mut out, "bhumi_relay_commits", "Unused commits held for all devices", gauges.commits);
+        gauge(&mut out, "bhumi_relay_in_flight_deliveries", "DELIVERs awaiting ACK", gauges.in_flight);
+        gauge(&mut out, "bhumi_relay_cached_responses", "Responses held for retries", gauges.cached_responses);
+        gauge(&mut out, "bhumi_relay_cached_bytes", "Bytes charged to the response cache", gauges.cached_bytes);
+
+        gauge(&mut out, "bhumi_relay_queued_deliveries", "DELIVERs queued for devices but not yet written", gauges.queued);
+        gauge(&mut out, "bhumi_relay_delivery_queue_max_depth", "DELIVERs queued for the most backed-up device", gauges.max_queue_depth);
+        gauge(&mut out, "bhumi_relay_full_delivery_queues", "Devices whose delivery queue is full", gauges.full_queues);
+
+        header(&mut out, "bhumi_relay_sends_total", "counter", "SEND_RESULTs by status");
+        for (status, counter) in self.sends.iter().enumerate() {
+            let value = counter.load(Ordering::Relaxed);
+            let label = STATUSES.iter().find(|(s, _)| *s as usize == status).map(|(_, l)| l.to_string());
+            if label.is_none() && value == 0 {
+                continue;
+            }
+            let label = label.unwrap_or_else(|| status.to_string());
+            let _ = writeln!(out, "bhumi_relay_sends_total{{status=\"{label}\"}} {value}");
+        }
+
+        header(&mut out, "bhumi_relay_cache_lookups_total", "counter", "Response cache lookups by SENDs");
+        let _ = writeln!(out, "bhumi_relay_cache_lookups_total{{result=\"hit\"}} {}", self.cache_hits.load(Ordering::Relaxed));
+        let _ = writeln!(out, "bhumi_relay_cache_lookups_total{{result=\"miss\"}} {}", self.cache_misses.load(Ordering::Relaxed));
+
+        header(&mut out, "bhumi_relay_relayed_bytes_total", "counter", "Payload bytes of successful SENDs");
+        let _ = writeln!(out, "bhumi_relay_relayed_bytes_total{{direction=\"request\"}} {}", self.request_bytes.load(Ordering::Relaxed));
+        let _ = writeln!(out, "bhumi_relay_relayed_bytes_total{{direction=\"response\"}} {}", self.response_bytes.load(Ordering::Relaxed));
+
+        let latency = &self.delivery_latency;
+        header(&mut out, "bhumi_relay_delivery_seconds", "histogram", "Time from DELIVER to ACK");
+        let mut count = 0;
+        for (i, bucket) in latency.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |le| le.to_string());
+            let _ = writeln!(out, "bhumi_relay_delivery_seconds_bucket{{le=\"{le}\"}} {count}");
+        }
+        let sum = latency.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "bhumi_relay_delivery_seconds_sum {sum}");
+        let _ = writeln!(out, "bhumi_relay_delivery_seconds_count {count}");
+        out
+    }
+}
+
+/// Values read from the router at scrape time
+pub struct Gauges {
+    pub devices: usize,
+    pub commits: usize,
+    pub in_flight: usize,
+    pub cached_responses: usize,
+    pub cached_bytes: usize,
+    /// DELIVERs queued but not yet written, over all devices
+    pub queued: usize,
+    /// Deepest single delivery queue
+    pub max_queue_depth: usize,
+    /// Devices whose delivery queue is full, so SENDs to them get BUSY
+    pub full_queues: usize,
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Serve `/metrics` and `/healthz` on `listener`
+pub async fn serve(listener: TcpListener, router: Arc<Router>) -> std::io::Result<()> {
+    http::serve(listener, move |r: Request<_>| {
+        let router = router.clone();
+        async move {
+            if r.method() != Method::GET {
+                return respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+            }
+            match r.uri().path() {
+                "/metrics" => text(router.metrics().render(&router)),
+                "/healthz" => respond(StatusCode::OK, "ok\n"),
+                _ => respond(StatusCode::NOT_FOUND, "not found\n"),
+            }
+        }
+    })
+    .await
+}
+
+fn text(body: String) -> HttpResponse {
+    let mut response = respond(StatusCode::OK, body);
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition() {
+        let metrics = Metrics::default();
+        metrics.delivered(Duration::from_millis(3));
+        metrics.delivered(Duration::from_millis(30));
+        metrics.delivered(Duration::from_secs(60));
+        metrics.send(SEND_OK, 10, 20);
+        metrics.send(SEND_ERR_TIMEOUT, 10, 0);
+        metrics.send(42, 0, 0);
+
+        let router = Router::new(&crate::config::Config::default(), Arc::new(fastn_id52::SecretKey::generate()));
+        let out = metrics.render(&router);
+        for line in [
+            "bhumi_relay_delivery_seconds_bucket{le=\"0.005\"} 1",
+            "bhumi_relay_delivery_seconds_bucket{le=\"0.05\"} 2",
+            "bhumi_relay_delivery_seconds_bucket{le=\"30\"} 2",
+            "bhumi_relay_delivery_seconds_bucket{le=\"+Inf\"} 3",
+            "bhumi_relay_delivery_seconds_count 3",
+            "bhumi_relay_sends_total{status=\"ok\"} 1",
+            "bhumi_relay_sends_total{status=\"timeout\"} 1",
+            "bhumi_relay_sends_total{status=\"not_connected\"} 0",
+            "bhumi_relay_sends_total{status=\"42\"} 1",
+            "bhumi_relay_relayed_bytes_total{direction=\"response\"} 20",
+            "bhumi_relay_connected_devices 0",
+            "bhumi_relay_full_delivery_queues 0",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {line:?} in:\n{out}");
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Per-source-address quotas: concurrent connections, SEND rate and abuse
+//! penalties (see `abuse`)
+//!
+//! Anonymous senders are only identifiable by address, so all limits are
+//! kept per IP. A connection holds a `SourceGuard` for its lifetime; the
+//! SEND token bucket outlives the connection until it has refilled, so
+//! reconnecting doesn't reset it.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::abuse::{AbuseTracker, Penalty};
+use crate::config::{Abuse, Quotas};
+
+struct Source {
+    connections: usize,
+    /// SEND tokens, refilled at `sends_per_sec` up to `send_burst`
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Source {
+    fn refill(&mut self, quotas: &Quotas, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * quotas.sends_per_sec as f64).min(quotas.send_burst as f64);
+        self.refilled_at = now;
+    }
+}
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    TooManyConnections,
+    Banned,
+}
+
+/// Connection and SEND accounting for all source addresses
+pub struct SourceLimits {
+    quotas: Quotas,
+    abuse: AbuseTracker,
+    sources: Mutex<HashMap<IpAddr, Source>>,
+    /// Table size that triggers the next sweep of idle sources
+    sweep_at: Mutex<usize>,
+}
+
+impl SourceLimits {
+    pub fn new(quotas: Quotas, abuse: Abuse) -> Arc<Self> {
+        Arc::new(Self {
+            quotas,
+            abuse: AbuseTracker::new(abuse),
+            sources: Mutex::new(HashMap::new()),
+            sweep_at: Mutex::new(1024),
+        })
+    }
+
+    pub fn abuse(&self) -> &AbuseTracker {
+        &self.abuse
+    }
+
+    /// Account a new connection from `ip`, unless it is banned or already
+    /// has `max_connections_per_ip` open
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Result<SourceGuard, Refused> {
+        if self.abuse.penalty(ip) == Penalty::Banned {
+            return Err(Refused::Banned);
+        }
+        let mut sources = self.sources.lock().unwrap();
+        self.sweep(&mut sources);
+        let source = sources.entry(ip).or_insert_with(|| Source {
+            connections: 0,
+            tokens: self.quotas.send_burst as f64,
+            refilled_at: Instant::now(),
+        });
+        if source.connections >= self.quotas.max_connections_per_ip {
+            return Err(Refused::TooManyConnections);
+        }
+        source.connections += 1;
+        Ok(SourceGuard { limits: self.clone(), ip })
+    }
+
+    /// Forget sources with no connections and a full bucket, once the table
+    /// has doubled since the last sweep
+    fn sweep(&self, sources: &mut HashMap<IpAddr, Source>) {
+        let mut sweep_at = self.sweep_at.lock().unwrap();
+        if sources.len() < *sweep_at {
+            return;
+        }
+        let now = Instant::now();
+        sources.retain(|_, source| {
+            source.refill(&self.quotas, now);
+            source.connections > 0 || source.tokens < self.quotas.send_burst as f64
+        });
+        *sweep_at = (sources.len() * 2).max(1024);
+    }
+}
+
+/// One open connection from an address; releases its slot on drop
+pub struct SourceGuard {
+    limits: Arc<SourceLimits>,
+    ip: IpAddr,
+}
+
+impl SourceGuard {
+    /// Take a SEND token; false if the address is over its SEND rate
+    pub fn allow_send(&self) -> bool {
+        let mut sources = self.limits.sources.lock().unwrap();
+        let Some(source) = sources.get_mut(&self.ip) else {
+            return false;
+        };
+        source.refill(&self.limits.quotas, Instant::now());
+        if source.tokens < 1.0 {
+            return false;
+        }
+        source.tokens -= 1.0;
+        true
+    }
+
+    /// Abuse penalty for the address right now
+    pub fn penalty(&self) -> Penalty {
+        self.limits.abuse.penalty(self.ip)
+    }
+
+    /// Count a SEND that failed in a way abusers cause (see `abuse`)
+    pub fn record_failure(&self) {
+        self.limits.abuse.record_failure(self.ip);
+    }
+}
+
+impl Drop for SourceGuard {
+    fn drop(&mut self) {
+        let mut sources = self.limits.sources.lock().unwrap();
+        if let Some(source) = sources.get_mut(&self.ip) {
+            source.connections -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_and_send_rate() {
+        let limits = SourceLimits::new(Quotas {
+            max_connections_per_ip: 2,
+            sends_per_sec: 1,
+            send_burst: 3,
+            ..Quotas::default()
+        }, Abuse::default());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let first = limits.connect(ip).unwrap();
+        let second = limits.connect(ip).unwrap();
+        assert_eq!(limits.connect(ip).err(), Some(Refused::TooManyConnections));
+        assert!(limits.connect("192.0.2.2".parse().unwrap()).is_ok());
+        drop(second);
+        let second = limits.connect(ip).unwrap();
+
+        // The burst is shared by all connections from the address
+        assert!(first.allow_send());
+        assert!(second.allow_send());
+        assert!(first.allow_send());
+        assert!(!second.allow_send());
+
+        // ...and survives reconnecting
+        drop((first, second));
+        let third = limits.connect(ip).unwrap();
+        assert!(!third.allow_send());
+
+        limits.abuse().ban(ip, std::time::Duration::from_secs(60));
+        assert_eq!(limits.connect(ip).err(), Some(Refused::Banned));
+    }
+}
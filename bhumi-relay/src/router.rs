@@ -1,11 +1,34 @@
 //! Router: maps id52 to connections, handles message routing and response caching
+//!
+//! Built for many connected devices: maps are sharded (`shard`), each
+//! device's commits have their own lock, and counters are atomics. No lock
+//! is held across an await.
 
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use rand::seq::IteratorRandom;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
-use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
 
-use bhumi_proto::{SEND_OK, SEND_ERR_NOT_CONNECTED, SEND_ERR_INVALID_PREIMAGE, SEND_ERR_TIMEOUT, SEND_ERR_DISCONNECTED};
+use bhumi_proto::{SEND_OK, SEND_ERR_NOT_CONNECTED, SEND_ERR_INVALID_PREIMAGE, SEND_ERR_TIMEOUT, SEND_ERR_DISCONNECTED, SEND_ERR_NOT_ENCRYPTED};
+use bhumi_proto::{Nack, SEND_ERR_BUSY, SEND_ERR_TOO_MANY_IN_FLIGHT};
+use bhumi_proto::{crypto, CAP_ENCRYPTION_REQUIRED, PresenceError, SignedPresence};
+use bhumi_proto::presence::unix_now;
+use bhumi_proto::Send as SendMsg;
+use bhumi_proto::redact::Id52;
+use bhumi_proto::RelayAddr;
+use fastn_id52::{PublicKey, SecretKey};
+
+use crate::cache::ResponseCache;
+use crate::config::{Config, Limits, Quotas, Timeouts};
+use crate::forward;
+use crate::metrics::{Gauges, Metrics};
+use crate::shard::ShardedMap;
 
 /// Message to send to a connected device
 pub struct PendingDelivery {
@@ -14,18 +37,62 @@ pub struct PendingDelivery {
     pub payload: Vec<u8>,
 }
 
-/// Cached response for idempotent retry
-struct CachedResponse {
-    response: Vec<u8>,
-    expires_at: Instant,
+/// PRESENCE is gossiped to at most this many peer relays at a time (§7.2)
+const GOSSIP_FANOUT: usize = 3;
+
+/// Relay-to-relay gossip link (inbound or outbound)
+struct PeerLink {
+    relay_id52: PublicKey,
+    sender: mpsc::Sender<SignedPresence>,
 }
 
 /// Per-device state
 struct DeviceState {
-    /// Valid commits for this device (SHA256 hashes)
-    commits: HashSet<[u8; 32]>,
+    /// Valid commits for this device (SHA256 hashes), with when each
+    /// expires if it was added with a TTL
+    commits: Mutex<HashMap<[u8; 32], Option<Instant>>>,
+    /// Capabilities announced in I_AM
+    capabilities: u32,
     /// Channel to send messages to this device
     sender: mpsc::Sender<PendingDelivery>,
+    /// Deliveries routed to this device and not yet answered
+    in_flight: Arc<AtomicUsize>,
+    /// Which registration this is; a session only unregisters its own
+    generation: u64,
+    /// Cancelled when a newer session registers the same id52
+    takeover: CancellationToken,
+}
+
+/// Counts one delivery against its recipient's in-flight quota while alive
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Count a delivery, unless `max` are already in flight
+    fn acquire(count: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max).then_some(n + 1)).ok()?;
+        Some(Self(count.clone()))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Delivery awaiting ACK, kept whole so it can be re-queued if the
+/// recipient reconnects before answering
+struct PendingAck {
+    to_id52: [u8; 32],
+    /// What the response is cached under
+    preimage: [u8; 32],
+    payload: Vec<u8>,
+    /// When the consumed commit would have expired, for restoring it after
+    /// a NACK that allows a retry
+    commit_expiry: Option<Instant>,
+    /// Completes the sender's request: the ACK's response, or the status
+    /// a NACK maps to
+    response_tx: oneshot::Sender<Result<Vec<u8>, u8>>,
 }
 
 /// Result of a send operation
@@ -37,199 +104,474 @@ pub struct SendOutcome {
 /// Router manages device connections and message routing
 pub struct Router {
     /// Map of id52 (public key bytes) to device state
-    devices: RwLock<HashMap<[u8; 32], DeviceState>>,
+    devices: ShardedMap<[u8; 32], Arc<DeviceState>>,
     /// Global response cache (keyed by preimage)
-    response_cache: RwLock<HashMap<[u8; 32], CachedResponse>>,
+    response_cache: Mutex<ResponseCache>,
+    /// How often `sweep` drops expired cache entries, presences and commits
+    sweep_interval: Duration,
     /// Pending deliveries waiting for ACK (keyed by msg_id)
-    /// Stores (preimage, response_tx) so we can cache response under correct preimage
-    pending: RwLock<HashMap<u32, ([u8; 32], oneshot::Sender<Vec<u8>>)>>,
+    pending: ShardedMap<u32, PendingAck>,
     /// Next message ID
-    next_msg_id: RwLock<u32>,
-    /// Response cache TTL
-    cache_ttl: Duration,
+    next_msg_id: AtomicU32,
+    next_generation: AtomicU64,
+    limits: Limits,
+    quotas: Quotas,
+    timeouts: Timeouts,
+    /// Latest verified PRESENCE per id52 (in memory only, dropped at TTL)
+    presence: ShardedMap<[u8; 32], SignedPresence>,
+    /// Peer relays we gossip PRESENCE with (keyed by link id)
+    peers: Mutex<HashMap<u64, PeerLink>>,
+    next_peer_id: AtomicU64,
+    /// Configured peer relays by id52: the only relays SENDs are forwarded to
+    peer_relays: HashMap<PublicKey, RelayAddr>,
+    /// Our own identity: devices name it in PRESENCE, and peer links are
+    /// signed with it
+    secret_key: Arc<SecretKey>,
+    relay_id52: PublicKey,
+    metrics: Metrics,
 }
 
 impl Router {
-    pub fn new() -> Arc<Self> {
+    /// Router for the relay whose identity is `secret_key`
+    pub fn new(config: &Config, secret_key: Arc<SecretKey>) -> Arc<Self> {
         Arc::new(Self {
-            devices: RwLock::new(HashMap::new()),
-            response_cache: RwLock::new(HashMap::new()),
-            pending: RwLock::new(HashMap::new()),
-            next_msg_id: RwLock::new(1),
-            cache_ttl: Duration::from_secs(300), // 5 minutes
+            devices: ShardedMap::new(),
+            response_cache: Mutex::new(ResponseCache::new(config.cache)),
+            sweep_interval: (config.cache.ttl() / 4).max(Duration::from_secs(1)),
+            pending: ShardedMap::new(),
+            next_msg_id: AtomicU32::new(1),
+            next_generation: AtomicU64::new(1),
+            limits: config.limits,
+            quotas: config.quotas,
+            timeouts: config.timeouts,
+            presence: ShardedMap::new(),
+            peers: Mutex::new(HashMap::new()),
+            next_peer_id: AtomicU64::new(1),
+            peer_relays: config
+                .peer_addrs()
+                .into_iter()
+                .filter_map(|addr| Some((addr.relay_id52?, addr)))
+                .collect(),
+            relay_id52: secret_key.public_key(),
+            secret_key,
+            metrics: Metrics::default(),
         })
     }
 
-    /// Register a device with its commits and recent responses
-    pub async fn register(
+    pub fn secret_key(&self) -> &SecretKey {
+        &self.secret_key
+    }
+
+    /// Configured peer relays, by id52
+    pub fn peer_relays(&self) -> impl Iterator<Item = (&PublicKey, &RelayAddr)> {
+        self.peer_relays.iter()
+    }
+
+    /// Whether `relay_id52` is a configured peer (the only relays whose
+    /// RELAY_PEER is accepted)
+    pub fn is_peer_relay(&self, relay_id52: &PublicKey) -> bool {
+        self.peer_relays.contains_key(relay_id52)
+    }
+
+    /// Configured limits, shared by sessions and gossip links
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Current device, commit, in-flight delivery and cache counts, and
+    /// the size of the delivery queues taken together
+    pub fn gauges(&self) -> Gauges {
+        let (mut devices, mut commits, mut queued, mut max_queue_depth, mut full_queues) = (0, 0, 0, 0, 0);
+        self.devices.for_each(|_, device| {
+            devices += 1;
+            commits += device.commits.lock().unwrap().len();
+            let depth = device.sender.max_capacity() - device.sender.capacity();
+            queued += depth;
+            max_queue_depth = max_queue_depth.max(depth);
+            if device.sender.capacity() == 0 {
+                full_queues += 1;
+            }
+        });
+        let (cached_responses, cached_bytes) = {
+            let cache = self.response_cache.lock().unwrap();
+            (cache.len(), cache.bytes())
+        };
+        Gauges {
+            devices,
+            commits,
+            in_flight: self.pending.len(),
+            cached_responses,
+            cached_bytes,
+            queued,
+            max_queue_depth,
+            full_queues,
+        }
+    }
+
+    /// Devices with DELIVERs queued but not yet written to them, deepest
+    /// queue first
+    pub fn queue_depths(&self) -> Vec<([u8; 32], usize)> {
+        let mut depths = Vec::new();
+        self.devices.for_each(|id52, device| {
+            let depth = device.sender.max_capacity() - device.sender.capacity();
+            if depth > 0 {
+                depths.push((*id52, depth));
+            }
+        });
+        depths.sort_by(|a, b| b.1.cmp(&a.1));
+        depths
+    }
+
+    /// Register a device with its commits and recent responses; returns the
+    /// generation to unregister it with
+    ///
+    /// The newest session for an id52 wins: a session already registered
+    /// for it has `takeover` cancelled (and should close), and deliveries
+    /// still awaiting its ACK are re-queued onto `sender`.
+    pub fn register(
         &self,
         id52: [u8; 32],
         commits: Vec<[u8; 32]>,
         recent_responses: Vec<([u8; 32], Vec<u8>)>,
+        capabilities: u32,
         sender: mpsc::Sender<PendingDelivery>,
-    ) {
-        let mut devices = self.devices.write().await;
-        let commit_set: HashSet<[u8; 32]> = commits.into_iter().collect();
-
-        println!(
-            "  Router: registered {} with {} commits",
-            data_encoding::BASE32_DNSSEC.encode(&id52),
-            commit_set.len()
-        );
-
-        devices.insert(id52, DeviceState {
-            commits: commit_set,
-            sender,
-        });
+        takeover: CancellationToken,
+    ) -> u64 {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let mut devices = self.devices.shard(&id52);
+        let commit_set: HashMap<[u8; 32], Option<Instant>> = commits.into_iter().map(|c| (c, None)).collect();
+
+        info!("Registered {} with {} commits", Id52(&id52), commit_set.len());
+
+        let previous = devices.remove(&id52);
+        let in_flight = match &previous {
+            Some(old) => {
+                info!("{} reconnected, closing its previous session", Id52(&id52));
+                old.takeover.cancel();
+                old.in_flight.clone()
+            }
+            None => Arc::new(AtomicUsize::new(0)),
+        };
+        devices.insert(id52, Arc::new(DeviceState {
+            commits: Mutex::new(commit_set),
+            capabilities,
+            sender: sender.clone(),
+            in_flight,
+            generation,
+            takeover,
+        }));
+        drop(devices);
+
+        if previous.is_some() {
+            self.requeue(id52, sender);
+        }
 
         // Populate response cache from recent responses
         if !recent_responses.is_empty() {
             let count = recent_responses.len();
-            let mut cache = self.response_cache.write().await;
-            let expires_at = Instant::now() + self.cache_ttl;
+            let mut cache = self.response_cache.lock().unwrap();
             for (preimage, response) in recent_responses {
-                cache.insert(preimage, CachedResponse { response, expires_at });
+                cache.insert(preimage, response);
             }
-            println!("  Router: loaded {} recent responses into cache", count);
+            debug!("Loaded {} recent responses into cache", count);
         }
-    }
 
-    /// Unregister a device
-    pub async fn unregister(&self, id52: &[u8; 32]) {
-        let mut devices = self.devices.write().await;
-        devices.remove(id52);
-        println!(
-            "  Router: unregistered {}",
-            data_encoding::BASE32_DNSSEC.encode(id52)
-        );
+        generation
     }
 
-    /// Add commits to an existing device
-    pub async fn add_commits(&self, id52: &[u8; 32], commits: Vec<[u8; 32]>) {
-        let mut devices = self.devices.write().await;
-        if let Some(device) = devices.get_mut(id52) {
-            for commit in commits {
-                device.commits.insert(commit);
+    /// Queue every delivery to `id52` that is still awaiting an ACK onto
+    /// its new session; the ACK completes the original request as usual
+    ///
+    /// Deliveries beyond the room in the new session's queue are failed
+    /// with SEND_ERR_BUSY, their commits given back, as if the queue had
+    /// been full when they were sent.
+    fn requeue(&self, id52: [u8; 32], sender: mpsc::Sender<PendingDelivery>) {
+        let mut deliveries = Vec::new();
+        self.pending.for_each(|msg_id, p| {
+            if p.to_id52 == id52 {
+                deliveries.push(PendingDelivery { msg_id: *msg_id, preimage: p.preimage, payload: p.payload.clone() });
             }
+        });
+        if deliveries.is_empty() {
+            return;
+        }
+        deliveries.sort_by_key(|d| d.msg_id);
+        debug!("Re-queuing {} deliveries onto the new session", deliveries.len());
+        for delivery in deliveries {
+            let msg_id = delivery.msg_id;
+            let status = match sender.try_send(delivery) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => SEND_ERR_BUSY,
+                Err(TrySendError::Closed(_)) => SEND_ERR_DISCONNECTED,
+            };
+            if let Some(entry) = self.take_pending(&id52, msg_id) {
+                debug!("Re-queuing msg_id {} failed with status {}", msg_id, status);
+                self.fail_pending(entry, status, true);
+            }
+        }
+    }
+
+    /// Unregister a device, unless a newer session has registered it since
+    pub fn unregister(&self, id52: &[u8; 32], generation: u64) {
+        if self.remove_device(id52, generation) {
+            info!("Unregistered {}", Id52(id52));
+        }
+    }
+
+    fn remove_device(&self, id52: &[u8; 32], generation: u64) -> bool {
+        let mut devices = self.devices.shard(id52);
+        let current = devices.get(id52).is_some_and(|d| d.generation == generation);
+        if current {
+            devices.remove(id52);
+        }
+        current
+    }
+
+    /// Add commits to an existing device, each with when it expires
+    ///
+    /// Re-adding a commit replaces its expiry. Returns false, adding
+    /// nothing, if the device would end up holding more than
+    /// `max_commits_per_device` unexpired commits.
+    pub fn add_commits(&self, id52: &[u8; 32], commits: Vec<([u8; 32], Option<Instant>)>) -> bool {
+        let Some(device) = self.device(id52) else {
+            return true;
+        };
+        let mut device_commits = device.commits.lock().unwrap();
+        let now = Instant::now();
+        device_commits.retain(|_, expires_at| !is_expired(*expires_at, now));
+        let new = commits.iter().filter(|(c, _)| !device_commits.contains_key(c)).count();
+        if device_commits.len() + new > self.quotas.max_commits_per_device {
+            return false;
         }
+        device_commits.extend(commits);
+        true
+    }
+
+    /// Withdraw commits from a device; returns how many it held
+    pub fn revoke_commits(&self, id52: &[u8; 32], commits: &[[u8; 32]]) -> usize {
+        let Some(device) = self.device(id52) else {
+            return 0;
+        };
+        let mut device_commits = device.commits.lock().unwrap();
+        commits.iter().filter(|c| device_commits.remove(*c).is_some()).count()
+    }
+
+    fn device(&self, id52: &[u8; 32]) -> Option<Arc<DeviceState>> {
+        self.devices.shard(id52).get(id52).cloned()
     }
 
     /// Handle ACK from a recipient
-    pub async fn handle_ack(&self, msg_id: u32, response: Vec<u8>) {
+    /// Handle ACK from `from`: cache the response and complete the send
+    pub fn handle_ack(&self, from: &[u8; 32], msg_id: u32, response: Vec<u8>) {
         // Get pending entry (includes preimage)
-        let entry = {
-            let mut pending = self.pending.write().await;
-            pending.remove(&msg_id)
-        };
+        let entry = self.take_pending(from, msg_id);
 
-        if let Some((preimage, tx)) = entry {
+        if let Some(entry) = entry {
             // Cache the response under the preimage
-            {
-                let mut cache = self.response_cache.write().await;
-                cache.insert(preimage, CachedResponse {
-                    response: response.clone(),
-                    expires_at: Instant::now() + self.cache_ttl,
-                });
-            }
+            self.response_cache.lock().unwrap().insert(entry.preimage, response.clone());
 
             // Complete the pending send
-            let _ = tx.send(response);
+            let _ = entry.response_tx.send(Ok(response));
         }
     }
 
+    /// Handle NACK from `from`: fail the send at once, giving the commit
+    /// back if the NACK allows a retry
+    pub fn handle_nack(&self, from: &[u8; 32], nack: Nack) {
+        let Some(entry) = self.take_pending(from, nack.msg_id) else {
+            return;
+        };
+        self.fail_pending(entry, nack.send_status(), nack.restores_commit());
+    }
+
+    /// Answer a delivery's sender with `status` instead of a response,
+    /// giving the commit back first if a retry may use it
+    fn fail_pending(&self, entry: PendingAck, status: u8, restore_commit: bool) {
+        if restore_commit
+            && let Some(device) = self.device(&entry.to_id52)
+        {
+            use sha2::{Sha256, Digest};
+            let commit: [u8; 32] = Sha256::digest(entry.preimage).into();
+            device.commits.lock().unwrap().insert(commit, entry.commit_expiry);
+            debug!("Commit restored for a retry");
+        }
+        let _ = entry.response_tx.send(Err(status));
+    }
+
+    /// Remove the delivery awaiting an answer under `msg_id`, if it went to
+    /// `from`: msg_ids are sequential, so a device could guess another's
+    fn take_pending(&self, from: &[u8; 32], msg_id: u32) -> Option<PendingAck> {
+        let mut pending = self.pending.shard(&msg_id);
+        if pending.get(&msg_id)?.to_id52 != *from {
+            debug!("Answer for a delivery to another device, ignored");
+            return None;
+        }
+        pending.remove(&msg_id)
+    }
+
     /// Try to route a message to a device (synchronous - waits for response)
+    ///
+    /// Waits for the recipient as long as `deadline_ms` asks, within the
+    /// configured bounds (0: the default delivery timeout). If the device
+    /// isn't connected here and `forward` is set, the SEND is passed to the
+    /// relay named in its PRESENCE.
     pub async fn route_message(
         &self,
         to_id52: [u8; 32],
         preimage: [u8; 32],
         payload: Vec<u8>,
+        deadline_ms: u32,
+        forward: bool,
     ) -> SendOutcome {
-        // 1. Check response cache first
-        {
-            let mut cache = self.response_cache.write().await;
-            if let Some(cached) = cache.remove(&preimage) {
-                if cached.expires_at > Instant::now() {
-                    println!("    -> cached response");
-                    return SendOutcome {
-                        status: SEND_OK,
-                        payload: cached.response,
-                    };
-                }
-            }
+        let wait = self.timeouts.delivery_for(deadline_ms);
+
+        // 1. Check response cache first; a hit stays cached for later retries
+        let cached = self.response_cache.lock().unwrap().get(&preimage);
+        self.metrics.cache_lookup(cached.is_some());
+        if let Some(response) = cached {
+            debug!("Answered from response cache");
+            return SendOutcome {
+                status: SEND_OK,
+                payload: response,
+            };
         }
 
         // 2. Compute commit from preimage
         use sha2::{Sha256, Digest};
-        let commit: [u8; 32] = Sha256::digest(&preimage).into();
+        let commit: [u8; 32] = Sha256::digest(preimage).into();
 
         // 3. Check recipient and validate commit
-        let (msg_id, sender) = {
-            let mut devices = self.devices.write().await;
-
-            let device = match devices.get_mut(&to_id52) {
-                Some(d) => d,
-                None => {
-                    println!("    -> ERR: device {} not in map",
-                        data_encoding::BASE32_DNSSEC.encode(&to_id52[..10]));
-                    return SendOutcome {
-                        status: SEND_ERR_NOT_CONNECTED,
-                        payload: Vec::new(),
-                    };
-                }
+        let local = 'local: {
+            let Some(device) = self.device(&to_id52) else {
+                debug!("Recipient not registered here");
+                break 'local None;
             };
 
             // Check if device's channel is still alive FIRST
             // (network issues can leave stale entries in map)
             if device.sender.is_closed() {
-                println!("    -> ERR: device {} channel closed (stale entry)",
-                    data_encoding::BASE32_DNSSEC.encode(&to_id52[..10]));
+                debug!("Recipient channel closed (stale entry)");
                 // Clean up stale entry
-                devices.remove(&to_id52);
+                self.remove_device(&to_id52, device.generation);
+                break 'local None;
+            }
+
+            // Refuse plaintext for devices that require encryption, before
+            // the commit is consumed (a sealed payload is at least OVERHEAD bytes)
+            if device.capabilities & CAP_ENCRYPTION_REQUIRED != 0 && payload.len() < crypto::OVERHEAD {
+                debug!("Refused: unencrypted payload for a device that requires encryption");
                 return SendOutcome {
-                    status: SEND_ERR_NOT_CONNECTED,
+                    status: SEND_ERR_NOT_ENCRYPTED,
                     payload: Vec::new(),
                 };
             }
 
-            // Check if commit is valid
-            if !device.commits.remove(&commit) {
-                println!("    -> ERR: invalid preimage for device {} (has {} commits, requested commit {})",
-                    data_encoding::BASE32_DNSSEC.encode(&to_id52[..10]),
-                    device.commits.len(),
-                    data_encoding::HEXLOWER.encode(&commit[..8]));
+            // Back-pressure from a recipient that isn't answering, also
+            // before the commit is consumed
+            let Some(in_flight) = InFlight::acquire(&device.in_flight, self.quotas.max_in_flight_per_recipient) else {
+                debug!("Refused: too many deliveries in flight to recipient");
                 return SendOutcome {
-                    status: SEND_ERR_INVALID_PREIMAGE,
+                    status: SEND_ERR_TOO_MANY_IN_FLIGHT,
                     payload: Vec::new(),
                 };
-            }
+            };
+
+            // A slot in the recipient's delivery queue, which fills when it
+            // isn't reading (a slow link or MCU); also before the commit is consumed
+            let permit = match device.sender.clone().try_reserve_owned() {
+                Ok(permit) => permit,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Refused: recipient's delivery queue is full");
+                    return SendOutcome {
+                        status: SEND_ERR_BUSY,
+                        payload: Vec::new(),
+                    };
+                }
+                Err(TrySendError::Closed(_)) => {
+                    debug!("Recipient channel closed (stale entry)");
+                    self.remove_device(&to_id52, device.generation);
+                    break 'local None;
+                }
+            };
 
-            // Generate message ID
-            let msg_id = {
-                let mut id = self.next_msg_id.write().await;
-                let current = *id;
-                *id += 1;
-                current
+            // Check the commit is valid and unexpired
+            let commit_expiry = {
+                let mut commits = device.commits.lock().unwrap();
+                match commits.remove(&commit) {
+                    Some(expires_at) if !is_expired(expires_at, Instant::now()) => expires_at,
+                    _ => {
+                        debug!("Refused: invalid preimage (recipient has {} commits)", commits.len());
+                        return SendOutcome {
+                            status: SEND_ERR_INVALID_PREIMAGE,
+                            payload: Vec::new(),
+                        };
+                    }
+                }
             };
 
-            (msg_id, device.sender.clone())
+            let msg_id = self.next_msg_id.fetch_add(1, Ordering::Relaxed);
+            Some((msg_id, commit_expiry, permit, in_flight))
         };
+        let Some((msg_id, commit_expiry, permit, _in_flight)) = local else {
+            if forward {
+                return self.forward_message(to_id52, preimage, payload, wait).await;
+            }
+            debug!("Recipient not connected");
+            return SendOutcome {
+                status: SEND_ERR_NOT_CONNECTED,
+                payload: Vec::new(),
+            };
+        };
+
+        let delivery = PendingDelivery { msg_id, preimage, payload };
+        self.deliver(to_id52, delivery, commit_expiry, wait, permit).await
+    }
+
+    /// Queue a DELIVER for a local recipient in the slot `permit` reserved,
+    /// and wait up to `wait` for its ACK
+    #[tracing::instrument(name = "msg", skip_all, fields(msg_id = delivery.msg_id))]
+    async fn deliver(
+        &self,
+        to_id52: [u8; 32],
+        delivery: PendingDelivery,
+        commit_expiry: Option<Instant>,
+        wait: Duration,
+        permit: mpsc::OwnedPermit<PendingDelivery>,
+    ) -> SendOutcome {
+        let msg_id = delivery.msg_id;
 
         // 4. Create response channel and register pending
         let (response_tx, response_rx) = oneshot::channel();
 
         // Register in pending map (before sending to avoid race)
-        {
-            let mut pending = self.pending.write().await;
-            pending.insert(msg_id, (preimage, response_tx));
-        }
+        let pending = PendingAck {
+            to_id52,
+            preimage: delivery.preimage,
+            payload: delivery.payload.clone(),
+            commit_expiry,
+            response_tx,
+        };
+        self.pending.shard(&msg_id).insert(msg_id, pending);
 
         // Queue delivery
-        let delivery = PendingDelivery { msg_id, preimage, payload };
+        let queued_at = std::time::Instant::now();
 
-        if sender.send(delivery).await.is_err() {
+        if permit.send(delivery).is_closed() {
             // Remove from pending on failure
-            let mut pending = self.pending.write().await;
-            pending.remove(&msg_id);
+            self.pending.shard(&msg_id).remove(&msg_id);
+            debug!("Recipient gone before DELIVER was queued");
             return SendOutcome {
                 status: SEND_ERR_DISCONNECTED,
                 payload: Vec::new(),
@@ -237,22 +579,35 @@ impl Router {
         }
 
         // 5. Wait for response with timeout
-        match tokio::time::timeout(Duration::from_secs(30), response_rx).await {
-            Ok(Ok(response)) => {
+        match tokio::time::timeout(wait, response_rx).await {
+            Ok(Ok(Ok(response))) => {
+                let latency = queued_at.elapsed();
+                self.metrics.delivered(latency);
+                debug!("ACK after {}ms", latency.as_millis());
                 SendOutcome {
                     status: SEND_OK,
                     payload: response,
                 }
             }
+            Ok(Ok(Err(status))) => {
+                debug!("NACK after {}ms", queued_at.elapsed().as_millis());
+                SendOutcome {
+                    status,
+                    payload: Vec::new(),
+                }
+            }
             Ok(Err(_)) => {
                 // Channel closed (recipient disconnected)
+                debug!("Recipient disconnected before ACK");
                 SendOutcome {
                     status: SEND_ERR_DISCONNECTED,
                     payload: Vec::new(),
                 }
             }
             Err(_) => {
-                // Timeout
+                // Timeout: a late ACK is dropped rather than kept around
+                self.pending.shard(&msg_id).remove(&msg_id);
+                debug!("No ACK within {}ms", wait.as_millis());
                 SendOutcome {
                     status: SEND_ERR_TIMEOUT,
                     payload: Vec::new(),
@@ -261,10 +616,403 @@ impl Router {
         }
     }
 
-    /// Cleanup expired cache entries (call periodically)
-    pub async fn cleanup_cache(&self) {
-        let mut cache = self.response_cache.write().await;
+    /// Forward a SEND to the relay the recipient last announced presence on,
+    /// caching a successful response here so a retry to this relay is
+    /// answered without contacting the recipient again
+    async fn forward_message(&self, to_id52: [u8; 32], preimage: [u8; 32], payload: Vec<u8>, wait: Duration) -> SendOutcome {
+        let relay_id52 = match self.lookup_presence(&to_id52) {
+            Some(signed) if signed.presence.relay_id52 != self.relay_id52.to_bytes() => signed.presence.relay_id52,
+            _ => {
+                debug!("Recipient not connected, no presence elsewhere");
+                return SendOutcome {
+                    status: SEND_ERR_NOT_CONNECTED,
+                    payload: Vec::new(),
+                };
+            }
+        };
+        let Some((relay_id52, relay)) = PublicKey::from_bytes(&relay_id52).ok().and_then(|id52| self.peer_relays.get_key_value(&id52)) else {
+            debug!("Recipient's presence names a relay that isn't a configured peer");
+            return SendOutcome {
+                status: SEND_ERR_NOT_CONNECTED,
+                payload: Vec::new(),
+            };
+        };
+
+        debug!("Forwarding to {} at {}", relay_id52, relay.addr);
+        let deadline_ms = wait.as_millis().try_into().unwrap_or(u32::MAX);
+        let send = SendMsg { to_id52, preimage, payload, request_id: 0, deadline_ms };
+        let result = forward::forward_send(*relay_id52, relay, self, send, self.timeouts.forward_for(wait)).await;
+
+        if result.status == SEND_OK {
+            self.response_cache.lock().unwrap().insert(preimage, result.payload.clone());
+        }
+
+        SendOutcome {
+            status: result.status,
+            payload: result.payload,
+        }
+    }
+
+    /// Store a PRESENCE if it verifies and is newer than the one we have,
+    /// and gossip it on to a few peer relays (never back to `from_peer`)
+    ///
+    /// Returns false if an equal or newer presence was already stored; those
+    /// are not gossiped again, which is what stops the flood.
+    pub fn store_presence(
+        &self,
+        signed: SignedPresence,
+        from_peer: Option<u64>,
+    ) -> Result<bool, PresenceError> {
+        signed.verify(unix_now())?;
+
+        {
+            let mut presence = self.presence.shard(&signed.presence.id52);
+            if let Some(existing) = presence.get(&signed.presence.id52)
+                && existing.presence.issued_at >= signed.presence.issued_at
+            {
+                return Ok(false);
+            }
+            presence.insert(signed.presence.id52, signed.clone());
+        }
+
+        self.gossip(signed, from_peer);
+        Ok(true)
+    }
+
+    /// Queue a PRESENCE for a random subset of peer links (best effort:
+    /// links with a full queue are skipped)
+    fn gossip(&self, signed: SignedPresence, from_peer: Option<u64>) {
+        let peers = self.peers.lock().unwrap();
+        let targets = peers
+            .iter()
+            .filter(|(id, _)| Some(**id) != from_peer)
+            .choose_multiple(&mut rand::thread_rng(), GOSSIP_FANOUT);
+        for (_, link) in targets {
+            if link.sender.try_send(signed.clone()).is_ok() {
+                debug!("Gossiped PRESENCE to {}", link.relay_id52);
+            }
+        }
+    }
+
+    /// Register a relay-to-relay link; returns its id for `unregister_peer`
+    pub fn register_peer(&self, relay_id52: PublicKey, sender: mpsc::Sender<SignedPresence>) -> u64 {
+        let id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
+        info!("Peer relay {} linked", relay_id52);
+        self.peers.lock().unwrap().insert(id, PeerLink { relay_id52, sender });
+        id
+    }
+
+    pub fn unregister_peer(&self, id: u64) {
+        if let Some(link) = self.peers.lock().unwrap().remove(&id) {
+            info!("Peer relay {} unlinked", link.relay_id52);
+        }
+    }
+
+    /// Latest unexpired PRESENCE for an id52, exactly as the owner signed it
+    pub fn lookup_presence(&self, id52: &[u8; 32]) -> Option<SignedPresence> {
+        let mut presence = self.presence.shard(id52);
+        let signed = presence.get(id52)?;
+        if signed.presence.expires_at() <= unix_now() {
+            presence.remove(id52);
+            return None;
+        }
+        Some(signed.clone())
+    }
+
+    /// Drop expired cached responses, presences and commits every
+    /// `sweep_interval`, so entries nobody asks for again don't hold memory
+    pub async fn sweep(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.sweep_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let responses = self.response_cache.lock().unwrap().sweep();
+
+            let now = unix_now();
+            let mut expired = Vec::new();
+            self.presence.for_each(|id52, signed| {
+                if signed.presence.expires_at() <= now {
+                    expired.push(*id52);
+                }
+            });
+            let mut presences = 0;
+            for id52 in expired {
+                let mut presence = self.presence.shard(&id52);
+                // Re-checked: a newer PRESENCE may have arrived meanwhile
+                if presence.get(&id52).is_some_and(|signed| signed.presence.expires_at() <= now) {
+                    presence.remove(&id52);
+                    presences += 1;
+                }
+            }
+            let mut commits = 0;
+            let now = Instant::now();
+            self.devices.for_each(|_, device| {
+                let mut device_commits = device.commits.lock().unwrap();
+                let before = device_commits.len();
+                device_commits.retain(|_, expires_at| !is_expired(*expires_at, now));
+                commits += before - device_commits.len();
+            });
+            if responses > 0 || presences > 0 || commits > 0 {
+                debug!("Swept {} cached responses, {} presences and {} commits", responses, presences, commits);
+            }
+        }
+    }
+}
+
+/// Whether a commit expiring at `expires_at` is past it at `now`
+fn is_expired(expires_at: Option<Instant>, now: Instant) -> bool {
+    expires_at.is_some_and(|t| t <= now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use tokio::task::JoinHandle;
+
+    const ID52: [u8; 32] = [1; 32];
+    const PREIMAGE: [u8; 32] = [2; 32];
+
+    fn setup() -> Arc<Router> {
+        Router::new(&Config::default(), Arc::new(SecretKey::generate()))
+    }
+
+    fn commit(preimage: [u8; 32]) -> [u8; 32] {
+        Sha256::digest(preimage).into()
+    }
+
+    /// Register `ID52` with a commit for each of `preimages` and room for
+    /// `queue` DELIVERs; returns its delivery queue and generation
+    fn registered_device(router: &Router, preimages: &[[u8; 32]], queue: usize) -> (mpsc::Receiver<PendingDelivery>, u64) {
+        let (tx, rx) = mpsc::channel(queue);
+        let commits = preimages.iter().copied().map(commit).collect();
+        let generation = router.register(ID52, commits, Vec::new(), 0, tx, CancellationToken::new());
+        (rx, generation)
+    }
+
+    /// SEND "hi" to `ID52` in the background, with the default deadline
+    fn send(router: &Arc<Router>, preimage: [u8; 32]) -> JoinHandle<SendOutcome> {
+        let router = router.clone();
+        tokio::spawn(async move { router.route_message(ID52, preimage, b"hi".to_vec(), 0, false).await })
+    }
+
+    #[tokio::test]
+    async fn newest_session_takes_over() {
+        let router = setup();
+        let (old_tx, mut old_rx) = mpsc::channel(4);
+        let old_takeover = CancellationToken::new();
+        let old = router.register(ID52, vec![commit(PREIMAGE)], Vec::new(), 0, old_tx, old_takeover.clone());
+
+        let send = send(&router, PREIMAGE);
+        let delivery = old_rx.recv().await.unwrap();
+
+        // The device reconnects before answering on the old session
+        let (mut new_rx, new) = registered_device(&router, &[], 4);
+        assert!(old_takeover.is_cancelled());
+        assert_ne!(old, new);
+
+        // The old session's cleanup leaves the new registration alone
+        router.unregister(&ID52, old);
+        assert_eq!(router.gauges().devices, 1);
+
+        let requeued = new_rx.recv().await.unwrap();
+        assert_eq!(requeued.msg_id, delivery.msg_id);
+        assert_eq!(requeued.payload, b"hi");
+        router.handle_ack(&ID52, requeued.msg_id, b"ok".to_vec());
+        let outcome = send.await.unwrap();
+        assert_eq!((outcome.status, outcome.payload), (SEND_OK, b"ok".to_vec()));
+
+        router.unregister(&ID52, new);
+        assert_eq!(router.gauges().devices, 0);
+    }
+
+    /// Deliveries re-queued beyond the new session's queue are answered
+    /// BUSY at once, and their commits can be used again
+    #[tokio::test]
+    async fn requeue_beyond_the_queue_is_busy() {
+        let router = setup();
+        let preimages = [[2; 32], [3; 32], [4; 32]];
+        let (old_tx, mut old_rx) = mpsc::channel(4);
+        let commits = preimages.iter().copied().map(commit).collect();
+        router.register(ID52, commits, Vec::new(), 0, old_tx, CancellationToken::new());
+
+        let sends: Vec<_> = preimages.iter().map(|p| send(&router, *p)).collect();
+        for _ in &preimages {
+            old_rx.recv().await.unwrap();
+        }
+
+        // Room for one of the three on the new session
+        let (mut new_rx, generation) = registered_device(&router, &[], 1);
+        let requeued = new_rx.recv().await.unwrap();
+        router.handle_ack(&ID52, requeued.msg_id, b"ok".to_vec());
+
+        let mut statuses = Vec::new();
+        for send in sends {
+            statuses.push(send.await.unwrap().status);
+        }
+        statuses.sort();
+        assert_eq!(statuses, vec![SEND_OK, SEND_ERR_BUSY, SEND_ERR_BUSY]);
+        assert_eq!(router.gauges().in_flight, 0);
+
+        // The refused ones kept their commits
+        let refused = preimages.into_iter().find(|p| *p != requeued.preimage).unwrap();
+        let retry = send(&router, refused);
+        let delivery = new_rx.recv().await.unwrap();
+        router.handle_ack(&ID52, delivery.msg_id, b"ok".to_vec());
+        assert_eq!(retry.await.unwrap().status, SEND_OK);
+        router.unregister(&ID52, generation);
+    }
+
+    /// Protocol §8.3: a SEND retried after its response was lost is answered
+    /// from the cache, as often as needed, without another DELIVER
+    #[tokio::test]
+    async fn retries_are_answered_from_cache() {
+        let router = setup();
+        let (mut rx, generation) = registered_device(&router, &[PREIMAGE], 4);
+
+        let send = send(&router, PREIMAGE);
+        let delivery = rx.recv().await.unwrap();
+        router.handle_ack(&ID52, delivery.msg_id, b"ok".to_vec());
+        assert_eq!(send.await.unwrap().status, SEND_OK);
+
+        // The commit is consumed, so only the cache can answer
+        for _ in 0..2 {
+            let outcome = router.route_message(ID52, PREIMAGE, b"hi".to_vec(), 0, false).await;
+            assert_eq!((outcome.status, outcome.payload), (SEND_OK, b"ok".to_vec()));
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(router.gauges().cached_responses, 1);
+
+        // Other preimages still need a commit
+        let outcome = router.route_message(ID52, [3; 32], b"hi".to_vec(), 0, false).await;
+        assert_eq!(outcome.status, SEND_ERR_INVALID_PREIMAGE);
+        router.unregister(&ID52, generation);
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_commits_are_refused() {
+        let router = setup();
+        let (mut rx, generation) = registered_device(&router, &[], 4);
+
         let now = Instant::now();
-        cache.retain(|_, v| v.expires_at > now);
+        assert!(router.add_commits(&ID52, vec![
+            (commit([2; 32]), Some(now)),
+            (commit([3; 32]), Some(now + Duration::from_secs(60))),
+            (commit([4; 32]), None),
+        ]));
+        assert_eq!(router.revoke_commits(&ID52, &[commit([3; 32]), commit([9; 32])]), 1);
+        for preimage in [[2; 32], [3; 32]] {
+            let outcome = router.route_message(ID52, preimage, b"hi".to_vec(), 0, false).await;
+            assert_eq!(outcome.status, SEND_ERR_INVALID_PREIMAGE);
+        }
+
+        // Expired commits don't count against the quota
+        let quota = router.quotas().max_commits_per_device;
+        let many: Vec<_> = (0..quota as u32 - 1).map(|i| {
+            let mut preimage = [0xff; 32];
+            preimage[..4].copy_from_slice(&i.to_be_bytes());
+            (commit(preimage), Some(now))
+        }).collect();
+        assert!(router.add_commits(&ID52, many));
+        assert!(router.add_commits(&ID52, vec![(commit([5; 32]), None)]));
+
+        let send = send(&router, [4; 32]);
+        let delivery = rx.recv().await.unwrap();
+        router.handle_ack(&ID52, delivery.msg_id, b"ok".to_vec());
+        assert_eq!(send.await.unwrap().status, SEND_OK);
+        router.unregister(&ID52, generation);
+    }
+
+    #[tokio::test]
+    async fn nack_fails_the_send_at_once() {
+        use bhumi_proto::{NACK_BUSY, NACK_MALFORMED, SEND_ERR_BUSY, SEND_ERR_REFUSED};
+
+        let router = setup();
+        let (mut rx, generation) = registered_device(&router, &[PREIMAGE], 4);
+
+        // Busy: the commit is given back, so the same preimage works again
+        // and only the last NACK uses it up
+        for (reason, status) in [(NACK_BUSY, SEND_ERR_BUSY), (NACK_MALFORMED, SEND_ERR_REFUSED)] {
+            let send = send(&router, PREIMAGE);
+            let delivery = rx.recv().await.unwrap();
+            router.handle_nack(&ID52, Nack { msg_id: delivery.msg_id, reason });
+            let outcome = send.await.unwrap();
+            assert_eq!((outcome.status, outcome.payload), (status, Vec::new()));
+        }
+        let outcome = router.route_message(ID52, PREIMAGE, b"hi".to_vec(), 0, false).await;
+        assert_eq!(outcome.status, SEND_ERR_INVALID_PREIMAGE);
+        assert_eq!(router.gauges().cached_responses, 0);
+        router.unregister(&ID52, generation);
+    }
+
+    #[tokio::test]
+    async fn answers_from_other_devices_are_ignored() {
+        use bhumi_proto::NACK_MALFORMED;
+
+        let router = setup();
+        let (mut rx, generation) = registered_device(&router, &[PREIMAGE], 4);
+
+        let send = send(&router, PREIMAGE);
+        let delivery = rx.recv().await.unwrap();
+
+        // Another device guessing the msg_id can neither fail nor answer it
+        let third_party = [9; 32];
+        router.handle_nack(&third_party, Nack { msg_id: delivery.msg_id, reason: NACK_MALFORMED });
+        router.handle_ack(&third_party, delivery.msg_id, b"forged".to_vec());
+        assert!(!send.is_finished());
+
+        router.handle_ack(&ID52, delivery.msg_id, b"ok".to_vec());
+        let outcome = send.await.unwrap();
+        assert_eq!((outcome.status, outcome.payload), (SEND_OK, b"ok".to_vec()));
+        router.unregister(&ID52, generation);
+    }
+
+    #[tokio::test]
+    async fn full_delivery_queue_is_busy() {
+        use bhumi_proto::SEND_ERR_BUSY;
+
+        let router = setup();
+        let preimages = [[2; 32], [3; 32]];
+        let (mut rx, generation) = registered_device(&router, &preimages, 1);
+
+        // The first DELIVER fills the queue and isn't read yet
+        let first = send(&router, preimages[0]);
+        while router.gauges().queued == 0 {
+            tokio::task::yield_now().await;
+        }
+        let gauges = router.gauges();
+        assert_eq!((gauges.queued, gauges.max_queue_depth, gauges.full_queues), (1, 1, 1));
+        assert_eq!(router.queue_depths(), vec![(ID52, 1)]);
+
+        // Refused at once, without consuming the commit
+        let outcome = router.route_message(ID52, preimages[1], b"hi".to_vec(), 0, false).await;
+        assert_eq!(outcome.status, SEND_ERR_BUSY);
+
+        let delivery = rx.recv().await.unwrap();
+        router.handle_ack(&ID52, delivery.msg_id, b"ok".to_vec());
+        assert_eq!(first.await.unwrap().status, SEND_OK);
+        assert_eq!(router.gauges().queued, 0);
+        assert!(router.queue_depths().is_empty());
+
+        let retry = send(&router, preimages[1]);
+        let delivery = rx.recv().await.unwrap();
+        router.handle_ack(&ID52, delivery.msg_id, b"ok".to_vec());
+        assert_eq!(retry.await.unwrap().status, SEND_OK);
+        router.unregister(&ID52, generation);
+    }
+
+    #[tokio::test]
+    async fn deadline_bounds_the_wait() {
+        let router = setup();
+        let (mut rx, generation) = registered_device(&router, &[PREIMAGE], 4);
+
+        // Raised to the 100ms minimum, well short of the 30s default
+        let started = Instant::now();
+        let outcome = router.route_message(ID52, PREIMAGE, b"hi".to_vec(), 1, false).await;
+        assert_eq!(outcome.status, SEND_ERR_TIMEOUT);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(rx.recv().await.is_some());
+        router.unregister(&ID52, generation);
     }
 }
@@ -1,48 +1,203 @@
-//! TCP server setup and connection handling (TLS disabled for dev)
+//! TCP/TLS server setup and connection handling
 
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
 
+use fastn_id52::SecretKey;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::gossip;
+use crate::metrics;
+use crate::quota::{SourceGuard, SourceLimits};
 use crate::router::Router;
 use crate::session::Session;
+use crate::tls::{self, Tls};
 
 pub struct Server {
-    listener: TcpListener,
+    /// Plaintext listener (local development)
+    plain: Option<TcpListener>,
+    tls: Option<(TcpListener, Arc<Tls>)>,
     router: Arc<Router>,
+    secret_key: Arc<SecretKey>,
+    sources: Arc<SourceLimits>,
+    /// Admin HTTP listener, if configured
+    admin: Option<(TcpListener, Arc<Admin>)>,
+    /// Metrics and health check listener, if configured
+    metrics: Option<TcpListener>,
+    tls_handshake_timeout: Duration,
 }
 
 impl Server {
-    pub async fn bind(addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let listener = TcpListener::bind(addr).await?;
-        let router = Router::new();
+    /// Relay with the given (validated) config; `secret_key` is the relay
+    /// identity. Listeners are added with the `listen_*` methods.
+    pub fn new(config: &Config, secret_key: SecretKey) -> Self {
+        info!("Relay id52: {}", secret_key.public_key());
+        let secret_key = Arc::new(secret_key);
+        Self {
+            plain: None,
+            tls: None,
+            router: Router::new(config, secret_key.clone()),
+            secret_key,
+            sources: SourceLimits::new(config.quotas, config.abuse.clone()),
+            admin: None,
+            metrics: None,
+            tls_handshake_timeout: config.timeouts.tls_handshake(),
+        }
+    }
 
-        println!("Relay listening on {} (TLS disabled)", addr);
+    /// Accept plaintext TCP on `addr`; id52s and preimages travel in the clear
+    pub async fn listen_plain(mut self, addr: &str) -> std::io::Result<Self> {
+        self.plain = Some(TcpListener::bind(addr).await?);
+        info!("Relay listening on {} (plaintext, development only)", addr);
+        Ok(self)
+    }
+
+    /// Accept TLS on `addr` with a PEM certificate chain and key, reloaded on SIGHUP
+    pub async fn listen_tls(mut self, addr: &str, cert: &Path, key: &Path) -> std::io::Result<Self> {
+        let tls = Tls::load(cert, key)?;
+        self.tls = Some((TcpListener::bind(addr).await?, tls));
+        info!("Relay listening on {} (TLS, {})", addr, cert.display());
+        Ok(self)
+    }
+
+    /// Accept TLS on `addr` with a self-signed certificate for the relay
+    /// identity (clients pin it with `?id52=`)
+    pub async fn listen_tls_identity(mut self, addr: &str) -> std::io::Result<Self> {
+        let tls = Tls::from_identity(&self.secret_key)?;
+        self.tls = Some((TcpListener::bind(addr).await?, tls));
+        info!("Relay listening on {} (TLS, identity certificate)", addr);
+        Ok(self)
+    }
 
-        Ok(Self {
-            listener,
-            router,
-        })
+    /// Serve the admin HTTP API on `addr`, requiring `token` if set
+    pub async fn listen_admin(mut self, addr: &str, token: Option<String>, max_ban: Duration) -> std::io::Result<Self> {
+        let admin = Admin::new(self.sources.clone(), self.router.clone(), token, max_ban);
+        self.admin = Some((TcpListener::bind(addr).await?, admin));
+        info!("Admin listening on {}", addr);
+        Ok(self)
+    }
+
+    /// Serve `/metrics` and `/healthz` on `addr`
+    pub async fn listen_metrics(mut self, addr: &str) -> std::io::Result<Self> {
+        self.metrics = Some(TcpListener::bind(addr).await?);
+        info!("Metrics listening on {}", addr);
+        Ok(self)
     }
 
     pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
-        loop {
-            let (stream, addr) = self.listener.accept().await?;
-            let router = self.router.clone();
+        if self.plain.is_none() && self.tls.is_none() {
+            return Err("no listener configured".into());
+        }
 
-            tokio::spawn(async move {
-                println!("Connection from {}", addr);
+        tokio::spawn(self.router.clone().sweep());
+        for (relay_id52, addr) in self.router.peer_relays() {
+            info!("Peering with relay {} at {}", relay_id52, addr.addr);
+            tokio::spawn(gossip::maintain_link(*relay_id52, addr.clone(), self.router.clone()));
+        }
 
-                // Generate random nonce
-                let nonce: u32 = rand::random();
+        let shared = Shared {
+            router: self.router.clone(),
+            secret_key: self.secret_key.clone(),
+            sources: self.sources.clone(),
+        };
+        let mut listeners = JoinSet::new();
+        if let Some(listener) = self.plain {
+            listeners.spawn(accept_plain(listener, shared.clone()));
+        }
+        if let Some((listener, tls)) = self.tls {
+            tokio::spawn(tls::reload_on_sighup(tls.clone()));
+            listeners.spawn(accept_tls(listener, tls, self.tls_handshake_timeout, shared));
+        }
+        if let Some((listener, admin)) = self.admin {
+            listeners.spawn(admin.serve(listener));
+        }
+        if let Some(listener) = self.metrics {
+            listeners.spawn(metrics::serve(listener, self.router.clone()));
+        }
 
-                let session = Session::new(stream, router, nonce);
-                if let Err(e) = session.run().await {
-                    if e.kind() != std::io::ErrorKind::UnexpectedEof {
-                        eprintln!("Session error with {}: {}", addr, e);
-                    }
-                }
-                println!("Connection closed: {}", addr);
-            });
+        // Listeners only return on error
+        if let Some(result) = listeners.join_next().await {
+            result??;
         }
+        Ok(())
+    }
+}
+
+/// What every session needs from the server
+#[derive(Clone)]
+struct Shared {
+    router: Arc<Router>,
+    secret_key: Arc<SecretKey>,
+    sources: Arc<SourceLimits>,
+}
+
+async fn accept_plain(listener: TcpListener, shared: Shared) -> std::io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let source = match shared.sources.connect(addr.ip()) {
+            Ok(source) => source,
+            Err(refused) => {
+                debug!("Connection from {} refused: {:?}", addr, refused);
+                continue;
+            }
+        };
+        tokio::spawn(run_session(stream, addr, false, shared.clone(), source));
+    }
+}
+
+async fn accept_tls(
+    listener: TcpListener,
+    tls: Arc<Tls>,
+    handshake_timeout: Duration,
+    shared: Shared,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        // Counted before the handshake, so handshakes can't be used to evade the limit
+        let source = match shared.sources.connect(addr.ip()) {
+            Ok(source) => source,
+            Err(refused) => {
+                debug!("Connection from {} refused: {:?}", addr, refused);
+                continue;
+            }
+        };
+        let acceptor = tls.acceptor();
+        let shared = shared.clone();
+
+        // Handshake in the session task so a slow client can't stall accept()
+        tokio::spawn(async move {
+            match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => run_session(stream, addr, true, shared, source).await,
+                Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+                Err(_) => warn!("TLS handshake with {} timed out", addr),
+            }
+        });
+    }
+}
+
+/// Everything logged for the connection is inside its `session` span; the
+/// session fills in `id52` after I_AM
+#[tracing::instrument(name = "session", skip_all, fields(peer = %addr, id52 = tracing::field::Empty))]
+async fn run_session<S>(stream: S, addr: std::net::SocketAddr, tls: bool, shared: Shared, source: SourceGuard)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    info!("Connection opened");
+
+    // Generate random nonce
+    let nonce: u32 = rand::random();
+
+    let session = Session::new(stream, tls, shared.router, shared.secret_key, source, nonce);
+    if let Err(e) = session.run().await
+        && e.kind() != std::io::ErrorKind::UnexpectedEof
+    {
+        warn!("Session error: {}", e);
     }
+    info!("Connection closed");
 }
@@ -1,48 +1,122 @@
 //! Session: handles a single device connection
 
+use futures::{SinkExt, StreamExt};
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, Instrument};
 
-use bhumi_proto::{Frame, Hello, IAm, Send as SendMsg, Deliver, Ack, SendResult, UpdateCommits, MSG_I_AM, MSG_SEND, MSG_ACK, MSG_UPDATE_COMMITS};
-use bhumi_proto::async_io::{read_frame, write_frame};
-use fastn_id52::PublicKey;
+use bhumi_proto::{BhumiCodec, Hello, IAm, Send as SendMsg, Deliver, Ack, SendResult, UpdateCommits, RevokeCommits, Nack, RelayMessage, ProtoError};
+use bhumi_proto::{PresenceQuery, PresenceResult, RelayPeer, SignedPresence};
+use bhumi_proto::{Frame, CAP_COMMIT_EXPIRY, CAP_ENCRYPTION_REQUIRED, CAP_KEEPALIVE, CAP_NACK, CAP_PRESENCE, CAP_RELAY_PEERING, CAP_REQUEST_IDS};
+use bhumi_proto::{SEND_ERR_INVALID_PREIMAGE, SEND_ERR_NOT_CONNECTED, SEND_ERR_RATE_LIMITED, SEND_ERR_TOO_MANY_IN_FLIGHT};
+use bhumi_proto::{SEND_ERR_BUSY, SEND_ERR_DISCONNECTED, SEND_ERR_NOT_ENCRYPTED, SEND_ERR_REFUSED, SEND_ERR_TIMEOUT, SEND_OK};
+use bhumi_proto::redact::Id52;
+use fastn_id52::{PublicKey, SecretKey};
 
-use crate::router::{Router, PendingDelivery};
+use crate::abuse::Penalty;
+use crate::quota::SourceGuard;
+use crate::router::{Router, PendingDelivery, SendOutcome};
+
+/// Optional features this relay supports, advertised in HELLO
+const RELAY_CAPABILITIES: u32 =
+    CAP_KEEPALIVE | CAP_ENCRYPTION_REQUIRED | CAP_PRESENCE | CAP_RELAY_PEERING | CAP_COMMIT_EXPIRY | CAP_NACK | CAP_REQUEST_IDS;
+
+/// A SEND routed in the background, for the session to answer
+struct Routed {
+    request_id: u32,
+    request_bytes: usize,
+    outcome: SendOutcome,
+    /// The SEND's span, to log the result in
+    span: tracing::Span,
+}
 
 pub struct Session<S> {
-    stream: S,
+    reader: FramedRead<ReadHalf<S>, BhumiCodec>,
+    writer: FramedWrite<WriteHalf<S>, BhumiCodec>,
+    /// Whether the connection came in over TLS (peer links must)
+    tls: bool,
+    /// Whether any frame has been read; RELAY_PEER must be the first
+    started: bool,
     router: Arc<Router>,
+    /// Relay identity HELLO is signed with
+    secret_key: Arc<SecretKey>,
+    /// Connection slot and SEND rate of the source address
+    source: SourceGuard,
     nonce: u32,
     id52: Option<[u8; 32]>,
+    /// Router registration of `id52`, so cleanup can't remove a newer session's
+    generation: u64,
+    /// Cancelled when another session registers the same id52
+    takeover: CancellationToken,
+    /// Version and capabilities from I_AM (None until the client identifies)
+    client: Option<(u8, u32)>,
+    /// Gossip link id if the other side is a relay that sent RELAY_PEER
+    peer: Option<u64>,
+    /// SENDs being routed, and where their tasks report back
+    routing: usize,
+    routed_tx: mpsc::Sender<Routed>,
+    routed_rx: mpsc::Receiver<Routed>,
 }
 
-impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
-    pub fn new(stream: S, router: Arc<Router>, nonce: u32) -> Self {
+impl<S: AsyncRead + AsyncWrite + Send + 'static> Session<S> {
+    pub fn new(stream: S, tls: bool, router: Arc<Router>, secret_key: Arc<SecretKey>, source: SourceGuard, nonce: u32) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let codec = BhumiCodec::with_max_payload(router.limits().max_payload_size as usize);
+        // Never full: SENDs beyond this many are refused before routing
+        let (routed_tx, routed_rx) = mpsc::channel(router.quotas().max_sends_per_session);
         Self {
-            stream,
+            reader: FramedRead::new(reader, codec.clone()),
+            writer: FramedWrite::new(writer, codec),
+            tls,
+            started: false,
             router,
+            secret_key,
+            source,
             nonce,
             id52: None,
+            generation: 0,
+            takeover: CancellationToken::new(),
+            client: None,
+            peer: None,
+            routing: 0,
+            routed_tx,
+            routed_rx,
         }
     }
 
     pub async fn run(mut self) -> std::io::Result<()> {
+        let limits = *self.router.limits();
+        let idle_timeout = self.router.timeouts().idle();
+
         // Send HELLO
-        let hello = Hello::new(self.nonce, 64 * 1024); // 64KB max payload
-        write_frame(&mut self.stream, &Frame::hello(&hello)).await?;
-        println!("  Sent HELLO (nonce=0x{:08x})", self.nonce);
+        let hello = Hello::new(self.nonce, limits.max_payload_size, RELAY_CAPABILITIES).sign(&self.secret_key);
+        self.write(hello).await?;
+        debug!("Sent HELLO (nonce=0x{:08x})", self.nonce);
 
         // Create channel for incoming deliveries
-        let (tx, mut rx) = mpsc::channel::<PendingDelivery>(32);
+        let (tx, mut rx) = mpsc::channel::<PendingDelivery>(limits.delivery_queue);
+        // Channel for PRESENCE gossip (only used if this is a peer relay)
+        let (gossip_tx, mut gossip_rx) = mpsc::channel::<SignedPresence>(limits.gossip_queue);
+
+        let mut last_seen = Instant::now();
+        let takeover = self.takeover.clone();
 
         // Main loop: handle incoming frames and outgoing deliveries
         loop {
             tokio::select! {
                 // Incoming frame from device
-                frame_result = read_frame(&mut self.stream) => {
+                frame_result = self.reader.next() => {
+                    let Some(frame_result) = frame_result else {
+                        break;
+                    };
                     let frame = frame_result?;
-                    if !self.handle_frame(frame, tx.clone()).await? {
+                    last_seen = Instant::now();
+                    if !self.handle_frame(frame, tx.clone(), &gossip_tx).await? {
                         break;
                     }
                 }
@@ -51,41 +125,79 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
                 Some(delivery) = rx.recv() => {
                     self.send_delivery(delivery).await?;
                 }
+
+                // A SEND from this session has been routed
+                Some(routed) = self.routed_rx.recv() => {
+                    self.finish_send(routed).await?;
+                }
+
+                // PRESENCE gossip to a peer relay
+                Some(presence) = gossip_rx.recv() => {
+                    self.write(presence).await?;
+                }
+
+                // Half-open or silent connection
+                _ = tokio::time::sleep_until(last_seen + idle_timeout), if self.idle_timeout_applies() => {
+                    info!("Idle for {}s, closing session", idle_timeout.as_secs());
+                    break;
+                }
+
+                // Same device connected again; newest session wins
+                _ = takeover.cancelled() => {
+                    info!("Replaced by a newer session, closing");
+                    break;
+                }
             }
         }
 
         // Cleanup
         if let Some(id52) = &self.id52 {
-            self.router.unregister(id52).await;
+            self.router.unregister(id52, self.generation);
+        }
+        if let Some(peer) = self.peer {
+            self.router.unregister_peer(peer);
         }
 
         Ok(())
     }
 
+    /// Version 1 devices never send KEEPALIVE, so they are never dropped as idle.
+    /// Anonymous (send-only) sessions are, whatever their version.
+    fn idle_timeout_applies(&self) -> bool {
+        match self.client {
+            Some((_, capabilities)) => capabilities & CAP_KEEPALIVE != 0,
+            None => true,
+        }
+    }
+
     async fn handle_frame(
         &mut self,
         frame: Frame,
         sender: mpsc::Sender<PendingDelivery>,
+        gossip: &mpsc::Sender<SignedPresence>,
     ) -> std::io::Result<bool> {
-        match frame.msg_type {
-            MSG_I_AM => {
-                let i_am = IAm::from_bytes(&frame.payload)?;
-                self.handle_i_am(i_am, sender).await?;
-            }
-            MSG_SEND => {
-                let send = SendMsg::from_bytes(&frame.payload)?;
-                self.handle_send(send).await?;
-            }
-            MSG_ACK => {
-                let ack = Ack::from_bytes(&frame.payload)?;
-                self.handle_ack(ack).await?;
-            }
-            MSG_UPDATE_COMMITS => {
-                let update = UpdateCommits::from_bytes(&frame.payload)?;
-                self.handle_update_commits(update).await?;
+        let first = !std::mem::replace(&mut self.started, true);
+        let msg = match frame.decode() {
+            Ok(msg) => msg,
+            Err(ProtoError::UnknownType(other)) => {
+                debug!("Unknown message type: 0x{:04x}", other);
+                return Ok(true);
             }
+            Err(e) => return Err(e.into()),
+        };
+        match msg {
+            RelayMessage::IAm(i_am) => self.handle_i_am(i_am, sender).await?,
+            RelayMessage::Send(send) => self.handle_send(send).await?,
+            RelayMessage::Ack(ack) => self.handle_ack(ack).await?,
+            RelayMessage::Nack(nack) => self.handle_nack(nack).await,
+            RelayMessage::UpdateCommits(update) => self.handle_update_commits(update).await?,
+            RelayMessage::RevokeCommits(revoke) => self.handle_revoke_commits(revoke).await,
+            RelayMessage::Keepalive => self.write(RelayMessage::Keepalive).await?,
+            RelayMessage::Presence(presence) => self.handle_presence(presence).await,
+            RelayMessage::PresenceQuery(query) => self.handle_presence_query(query).await?,
+            RelayMessage::RelayPeer(peer) => self.handle_relay_peer(peer, first, gossip)?,
             other => {
-                println!("  Unknown message type: 0x{:04x}", other);
+                debug!("Unexpected message type: 0x{:04x}", other.msg_type());
             }
         }
         Ok(true)
@@ -96,6 +208,10 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
         i_am: IAm,
         sender: mpsc::Sender<PendingDelivery>,
     ) -> std::io::Result<()> {
+        if self.peer.is_some() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "I_AM on a relay peer link"));
+        }
+
         // Verify signature: Sign(nonce || id52)
         let mut msg = Vec::with_capacity(4 + 32);
         msg.extend_from_slice(&self.nonce.to_be_bytes());
@@ -110,16 +226,25 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
         public_key.verify(&msg, &signature)
             .map_err(|_| std::io::Error::new(std::io::ErrorKind::PermissionDenied, "signature verification failed"))?;
 
-        println!(
-            "  I_AM verified: {} ({} commits, {} recent responses)",
-            public_key,
+        if i_am.version == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unsupported protocol version 0"));
+        }
+        if i_am.commits.len() > self.router.quotas().max_commits_per_device {
+            return Err(commit_quota_exceeded());
+        }
+
+        tracing::Span::current().record("id52", tracing::field::display(Id52(&i_am.id52)));
+        info!(
+            "I_AM verified (v{}, caps 0x{:x}, {} commits, {} recent responses)",
+            i_am.version,
+            i_am.capabilities,
             i_am.commits.len(),
             i_am.recent_responses.len()
         );
 
         // Unregister old identity if any
         if let Some(old_id52) = self.id52.take() {
-            self.router.unregister(&old_id52).await;
+            self.router.unregister(&old_id52, self.generation);
         }
 
         // Convert recent_responses to format expected by router
@@ -130,66 +255,262 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
 
         // Register new identity
         self.id52 = Some(i_am.id52);
-        self.router.register(i_am.id52, i_am.commits, recent_responses, sender).await;
+        self.client = Some((i_am.version, i_am.capabilities));
+        self.generation = self.router.register(
+            i_am.id52,
+            i_am.commits,
+            recent_responses,
+            i_am.capabilities,
+            sender,
+            self.takeover.clone(),
+        );
 
         Ok(())
     }
 
+    #[tracing::instrument(name = "send", skip_all, fields(to = %Id52(&send.to_id52)))]
     async fn handle_send(&mut self, send: SendMsg) -> std::io::Result<()> {
-        let to_id52_str = data_encoding::BASE32_DNSSEC.encode(&send.to_id52);
-        println!(
-            "  SEND to {} ({} bytes payload)",
-            to_id52_str,
-            send.payload.len()
-        );
+        debug!("SEND ({} bytes payload)", send.payload.len());
+
+        let delay = match self.source.penalty() {
+            Penalty::None => None,
+            Penalty::Delay(delay) => Some(delay),
+            Penalty::Banned => {
+                return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "source address banned"));
+            }
+        };
 
-        let outcome = self.router.route_message(send.to_id52, send.preimage, send.payload).await;
+        // Refused before routing, so the commit isn't consumed
+        if !self.source.allow_send() {
+            debug!("Refused: rate limited");
+            self.router.metrics().send(SEND_ERR_RATE_LIMITED, send.payload.len(), 0);
+            return self.write(SendResult::error(SEND_ERR_RATE_LIMITED).with_request_id(send.request_id)).await;
+        }
+        if self.routing >= self.router.quotas().max_sends_per_session {
+            debug!("Refused: too many SENDs in flight on this session");
+            self.router.metrics().send(SEND_ERR_TOO_MANY_IN_FLIGHT, send.payload.len(), 0);
+            return self.write(SendResult::error(SEND_ERR_TOO_MANY_IN_FLIGHT).with_request_id(send.request_id)).await;
+        }
+
+        // Routed in its own task, so this session keeps reading frames (and
+        // delivering to its device) while the recipient answers, or while
+        // an abuse penalty holds this SEND back
+        let router = self.router.clone();
+        let routed_tx = self.routed_tx.clone();
+        // SENDs a peer relay forwarded to us are not forwarded again
+        let forward = self.peer.is_none();
+        self.routing += 1;
+        tokio::spawn(async move {
+            if let Some(delay) = delay {
+                debug!("Delayed {}ms (abuse score)", delay.as_millis());
+                tokio::time::sleep(delay).await;
+            }
+            let request_bytes = send.payload.len();
+            let outcome = router.route_message(send.to_id52, send.preimage, send.payload, send.deadline_ms, forward).await;
+            let span = tracing::Span::current();
+            // Fails only if the session has closed
+            let _ = routed_tx.send(Routed { request_id: send.request_id, request_bytes, outcome, span }).await;
+        }.in_current_span());
+
+        Ok(())
+    }
+
+    async fn finish_send(&mut self, routed: Routed) -> std::io::Result<()> {
+        self.routing -= 1;
+        let Routed { request_id, request_bytes, outcome, span } = routed;
+
+        // Guessing preimages or probing for id52s
+        if outcome.status == SEND_ERR_INVALID_PREIMAGE || outcome.status == SEND_ERR_NOT_CONNECTED {
+            self.source.record_failure();
+        }
 
         let status_str = match outcome.status {
-            0 => "success",
-            1 => "not connected",
-            2 => "invalid preimage",
-            3 => "timeout",
-            4 => "disconnected",
+            SEND_OK => "success",
+            SEND_ERR_NOT_CONNECTED => "not connected",
+            SEND_ERR_INVALID_PREIMAGE => "invalid preimage",
+            SEND_ERR_TIMEOUT => "timeout",
+            SEND_ERR_DISCONNECTED => "disconnected",
+            SEND_ERR_NOT_ENCRYPTED => "not encrypted",
+            SEND_ERR_RATE_LIMITED => "rate limited",
+            SEND_ERR_TOO_MANY_IN_FLIGHT => "too many in flight",
+            SEND_ERR_BUSY => "busy",
+            SEND_ERR_REFUSED => "refused",
             _ => "unknown",
         };
-        println!("    -> {} ({} bytes response)", status_str, outcome.payload.len());
+        span.in_scope(|| debug!("SEND_RESULT: {} ({} bytes response)", status_str, outcome.payload.len()));
+        self.router.metrics().send(outcome.status, request_bytes, outcome.payload.len());
 
         // Send SEND_RESULT back to sender
         let result = SendResult {
             status: outcome.status,
             payload: outcome.payload,
+            request_id,
         };
-        write_frame(&mut self.stream, &Frame::send_result(&result)).await?;
-
-        Ok(())
+        self.write(result).await
     }
 
+    #[tracing::instrument(name = "msg", skip_all, fields(msg_id = ack.msg_id))]
     async fn handle_ack(&mut self, ack: Ack) -> std::io::Result<()> {
-        println!("  ACK for msg_id={} ({} bytes)", ack.msg_id, ack.payload.len());
-        self.router.handle_ack(ack.msg_id, ack.payload).await;
+        debug!("ACK ({} bytes)", ack.payload.len());
+        match &self.id52 {
+            Some(id52) => self.router.handle_ack(id52, ack.msg_id, ack.payload),
+            None => debug!("ACK: no identity registered"),
+        }
         Ok(())
     }
 
+    #[tracing::instrument(name = "msg", skip_all, fields(msg_id = nack.msg_id))]
+    async fn handle_nack(&mut self, nack: Nack) {
+        debug!("NACK (reason {})", nack.reason);
+        match &self.id52 {
+            Some(id52) => self.router.handle_nack(id52, nack),
+            None => debug!("NACK: no identity registered"),
+        }
+    }
+
     async fn handle_update_commits(&mut self, update: UpdateCommits) -> std::io::Result<()> {
         if let Some(id52) = &self.id52 {
-            let count = update.commits.len();
-            self.router.add_commits(id52, update.commits).await;
-            println!("  UPDATE_COMMITS: added {} commits", count);
+            let now = std::time::Instant::now();
+            let commits: Vec<_> = update.commits.iter().enumerate()
+                .map(|(i, commit)| (*commit, update.ttl(i).map(|ttl| now + Duration::from_secs(ttl.into()))))
+                .collect();
+            let count = commits.len();
+            let expiring = commits.iter().filter(|(_, expires_at)| expires_at.is_some()).count();
+            if !self.router.add_commits(id52, commits) {
+                return Err(commit_quota_exceeded());
+            }
+            debug!("UPDATE_COMMITS: added {} commits ({} with a TTL)", count, expiring);
         } else {
-            println!("  UPDATE_COMMITS: no identity registered");
+            debug!("UPDATE_COMMITS: no identity registered");
         }
         Ok(())
     }
 
+    async fn handle_revoke_commits(&mut self, revoke: RevokeCommits) {
+        if let Some(id52) = &self.id52 {
+            let revoked = self.router.revoke_commits(id52, &revoke.commits);
+            debug!("REVOKE_COMMITS: revoked {} of {} commits", revoked, revoke.commits.len());
+        } else {
+            debug!("REVOKE_COMMITS: no identity registered");
+        }
+    }
+
+    /// Turn this session into a gossip link, if a configured peer relay
+    /// opened it over TLS and proves its id52 by signing our nonce
+    fn handle_relay_peer(
+        &mut self,
+        peer: RelayPeer,
+        first: bool,
+        gossip: &mpsc::Sender<SignedPresence>,
+    ) -> std::io::Result<()> {
+        if !first {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "RELAY_PEER on an established session",
+            ));
+        }
+        if !self.tls {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "RELAY_PEER over plaintext"));
+        }
+        let relay_id52 = peer.verified_relay_id52(self.nonce)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::PermissionDenied, "RELAY_PEER signature verification failed"))?;
+        if !self.router.is_peer_relay(&relay_id52) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("RELAY_PEER from {}, not a configured peer", relay_id52),
+            ));
+        }
+        info!("RELAY_PEER from {}", relay_id52);
+        self.peer = Some(self.router.register_peer(relay_id52, gossip.clone()));
+        Ok(())
+    }
+
+    async fn handle_presence(&mut self, signed: SignedPresence) {
+        // Peer relays pass on anyone's (signed) presence; a device may only
+        // assert its own
+        if self.peer.is_none() && self.id52 != Some(signed.presence.id52) {
+            debug!("PRESENCE: not for this session's id52, ignored");
+            return;
+        }
+        match self.router.store_presence(signed, self.peer) {
+            Ok(true) => debug!("PRESENCE: stored"),
+            Ok(false) => debug!("PRESENCE: older than stored, ignored"),
+            Err(e) => debug!("PRESENCE: {}, ignored", e),
+        }
+    }
+
+    async fn handle_presence_query(&mut self, query: PresenceQuery) -> std::io::Result<()> {
+        let presence = self.router.lookup_presence(&query.id52);
+        debug!(
+            "PRESENCE_QUERY for {}: {}",
+            Id52(&query.id52),
+            if presence.is_some() { "found" } else { "not found" }
+        );
+        self.write(PresenceResult { presence }).await
+    }
+
+    #[tracing::instrument(name = "msg", skip_all, fields(msg_id = delivery.msg_id))]
     async fn send_delivery(&mut self, delivery: PendingDelivery) -> std::io::Result<()> {
         let deliver = Deliver {
             msg_id: delivery.msg_id,
             preimage: delivery.preimage,
             payload: delivery.payload,
         };
-        write_frame(&mut self.stream, &Frame::deliver(&deliver)).await?;
-        println!("  Sent DELIVER (msg_id={})", delivery.msg_id);
+        self.write(deliver).await?;
+        debug!("Sent DELIVER");
         Ok(())
     }
+
+    async fn write(&mut self, msg: impl Into<RelayMessage>) -> std::io::Result<()> {
+        self.writer.send(msg.into()).await
+    }
+}
+
+fn commit_quota_exceeded() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "commit quota exceeded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::quota::SourceLimits;
+
+    /// A device that announced CAP_KEEPALIVE and then goes silent is
+    /// dropped after `idle_secs`, and unregistered
+    #[tokio::test(start_paused = true)]
+    async fn silent_device_is_dropped() {
+        let config = Config::default();
+        let idle = config.timeouts.idle();
+        let secret_key = Arc::new(SecretKey::generate());
+        let router = Router::new(&config, secret_key.clone());
+        let source = SourceLimits::new(config.quotas, config.abuse).connect([127, 0, 0, 1].into()).unwrap();
+
+        let (relay_side, device_side) = tokio::io::duplex(64 * 1024);
+        let session = tokio::spawn(Session::new(relay_side, false, router.clone(), secret_key, source, 7).run());
+
+        let (reader, writer) = tokio::io::split(device_side);
+        let mut reader = FramedRead::new(reader, BhumiCodec::new());
+        let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+        let Ok(RelayMessage::Hello(hello)) = reader.next().await.unwrap().unwrap().decode() else {
+            panic!("expected HELLO");
+        };
+        let device = SecretKey::generate();
+        let id52 = device.public_key().to_bytes();
+        let mut signed = hello.nonce.to_be_bytes().to_vec();
+        signed.extend_from_slice(&id52);
+        let i_am = IAm::new(id52, device.sign(&signed).to_bytes(), Vec::new()).with_capabilities(CAP_KEEPALIVE);
+        writer.send(RelayMessage::from(i_am)).await.unwrap();
+        while router.gauges().devices == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // Still there just short of the timeout
+        tokio::time::sleep(idle - Duration::from_secs(1)).await;
+        assert_eq!(router.gauges().devices, 1);
+
+        let closed = tokio::time::timeout(Duration::from_secs(2), session).await;
+        closed.expect("session still open past the idle timeout").unwrap().unwrap();
+        assert_eq!(router.gauges().devices, 0);
+    }
 }
@@ -0,0 +1,68 @@
+//! Sharded hash map: one lock per shard instead of one for the whole map,
+//! so operations on different keys rarely wait on each other
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::{Mutex, MutexGuard};
+
+/// Power of two; at 10k devices that is ~150 per shard
+const SHARDS: usize = 64;
+
+pub struct ShardedMap<K, V> {
+    shards: Box<[Mutex<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Lock the shard `key` lives in (never hold it across an await)
+    pub fn shard(&self, key: &K) -> MutexGuard<'_, HashMap<K, V>> {
+        let index = self.hasher.hash_one(key) as usize & (SHARDS - 1);
+        self.shards[index].lock().unwrap()
+    }
+
+    /// Visit every entry, locking one shard at a time (so not a snapshot)
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        for shard in self.shards.iter() {
+            for (key, value) in shard.lock().unwrap().iter() {
+                f(key, value);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_keys_over_shards() {
+        let map = ShardedMap::new();
+        for i in 0..1000u32 {
+            map.shard(&i).insert(i, i * 2);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.shard(&7).get(&7), Some(&14));
+        assert!(map.shards.iter().all(|shard| !shard.lock().unwrap().is_empty()));
+
+        let mut sum = 0;
+        map.for_each(|_, v| sum += v);
+        assert_eq!(sum, 999 * 1000);
+    }
+}
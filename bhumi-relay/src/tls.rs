@@ -0,0 +1,151 @@
+//! TLS: certificate loading and SIGHUP reload for the TLS listener
+//!
+//! Without certificate files the relay can serve a self-signed certificate
+//! whose key is its Ed25519 identity, for clients pinning `?id52=`.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tracing::{error, info, warn};
+
+use fastn_id52::SecretKey;
+
+/// PKCS#8 v1 wrapper for a raw Ed25519 seed (RFC 8410)
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// TLS acceptor built from PEM files, swapped in place on reload
+pub struct Tls {
+    /// Certificate and key files (None for the identity certificate)
+    files: Option<(PathBuf, PathBuf)>,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl Tls {
+    /// Load the certificate chain and private key (PEM)
+    pub fn load(cert_path: &Path, key_path: &Path) -> std::io::Result<Arc<Self>> {
+        let acceptor = load_acceptor(cert_path, key_path)?;
+        Ok(Arc::new(Self {
+            files: Some((cert_path.to_path_buf(), key_path.to_path_buf())),
+            acceptor: RwLock::new(acceptor),
+        }))
+    }
+
+    /// Self-signed certificate with the relay identity as its key
+    pub fn from_identity(secret_key: &SecretKey) -> std::io::Result<Arc<Self>> {
+        let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+        pkcs8.extend_from_slice(&secret_key.to_bytes());
+        let pkcs8 = PrivatePkcs8KeyDer::from(pkcs8);
+
+        let key_pair = rcgen::KeyPair::from_pkcs8_der_and_sign_algo(&pkcs8, &rcgen::PKCS_ED25519)
+            .map_err(|e| invalid(e.to_string()))?;
+        let cert = rcgen::CertificateParams::new(vec![secret_key.id52()])
+            .and_then(|params| params.self_signed(&key_pair))
+            .map_err(|e| invalid(e.to_string()))?;
+
+        let acceptor = acceptor(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(pkcs8))?;
+        Ok(Arc::new(Self { files: None, acceptor: RwLock::new(acceptor) }))
+    }
+
+    /// Acceptor for a new connection (sessions already running keep theirs)
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Re-read the files; on error the current certificate stays in use
+    pub fn reload(&self) -> std::io::Result<()> {
+        let Some((cert_path, key_path)) = &self.files else {
+            return Ok(());
+        };
+        let acceptor = load_acceptor(cert_path, key_path)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        info!("TLS: reloaded {}", cert_path.display());
+        Ok(())
+    }
+}
+
+/// Reload certificates whenever the process gets SIGHUP
+pub async fn reload_on_sighup(tls: Arc<Tls>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("TLS: can't listen for SIGHUP, reload disabled: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = tls.reload() {
+            error!("TLS: reload failed, keeping current certificate: {}", e);
+        }
+    }
+}
+
+fn load_acceptor(cert_path: &Path, key_path: &Path) -> std::io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in {}", cert_path.display())));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid(format!("no private key in {}", key_path.display())))?;
+
+    acceptor(certs, key)
+}
+
+fn acceptor(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> std::io::Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &Path) -> (PathBuf, PathBuf) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn load_and_reload() {
+        let dir = std::env::temp_dir().join(format!("bhumi-relay-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = write_cert(&dir);
+
+        let tls = Tls::load(&cert_path, &key_path).unwrap();
+        write_cert(&dir);
+        tls.reload().unwrap();
+
+        // A broken file fails the reload but leaves the old acceptor usable
+        std::fs::write(&key_path, "not a key").unwrap();
+        assert!(tls.reload().is_err());
+        let _ = tls.acceptor();
+
+        assert!(Tls::load(&cert_path, &key_path).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn identity_certificate() {
+        let tls = Tls::from_identity(&SecretKey::generate()).unwrap();
+        tls.reload().unwrap();
+        let _ = tls.acceptor();
+    }
+}
@@ -0,0 +1,176 @@
+//! Relays on localhost: a SEND to relay B reaches a device on relay A
+//! through A's gossiped PRESENCE, only configured relays can open peer
+//! links, clients can pin a relay's identity, and one connection can have
+//! several SENDs in flight
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use bhumi_node::{json, Connection, Node, NodeConfig, PeerRole, RelayAddr, SecretKey, SEND_OK};
+use bhumi_proto::{BhumiCodec, RelayMessage, RelayPeer};
+use futures::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Kills the relay process when the test ends
+struct Relay(Child);
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Start a relay whose identity lives in `relay_home`, with a plaintext
+/// listener on `addr` and, if given, an identity TLS listener for peers
+async fn start_relay(relay_home: &std::path::Path, addr: &str, tls_addr: Option<&str>, peers: &str) -> Relay {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bhumi-relay"));
+    command
+        .env("BHUMI_HOME", relay_home)
+        .env("BHUMI_RELAY_ADDR", addr)
+        .env("BHUMI_RELAY_PEERS", peers)
+        .env("BHUMI_RELAY_LOG", "error")
+        .stdout(Stdio::null());
+    if let Some(tls_addr) = tls_addr {
+        command.env("BHUMI_RELAY_TLS_ADDR", tls_addr);
+    }
+    let relay = Relay(command.spawn().unwrap());
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return relay;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("relay {} did not start", addr);
+}
+
+fn home(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bhumi-forwarding-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Relay home with a known identity
+fn relay_home(name: &str) -> (PathBuf, SecretKey) {
+    let dir = home(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let key = SecretKey::generate();
+    std::fs::write(dir.join("relay.secret"), key.to_string()).unwrap();
+    (dir, key)
+}
+
+#[tokio::test]
+async fn send_is_forwarded_to_the_recipients_relay() {
+    let (relay_a, relay_a_tls) = (free_addr(), free_addr());
+    let (relay_b, relay_b_tls) = (free_addr(), free_addr());
+    let (home_a, key_a) = relay_home("relay-a");
+    let (home_b, key_b) = relay_home("relay-b");
+    // Each relay only accepts RELAY_PEER from relays it peers with itself
+    let peer_a = format!("tls://{relay_a_tls}?id52={}", key_a.public_key());
+    let peer_b = format!("tls://{relay_b_tls}?id52={}", key_b.public_key());
+    let _a = start_relay(&home_a, &relay_a, Some(&relay_a_tls), &peer_b).await;
+    let _b = start_relay(&home_b, &relay_b, Some(&relay_b_tls), &peer_a).await;
+    // Let B open its gossip link to A
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let mut switch = Node::new(home("switch"), NodeConfig::default());
+    switch.command("status", |_ctx, _state, _args| Ok(json!({ "is_on": true })));
+    let token = switch.create_invite("owner", PeerRole::Owner);
+    let switch_relay = relay_a.clone();
+    // Node::run isn't Send, so give the device its own runtime
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let _ = rt.block_on(switch.run(&switch_relay));
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let mut owner = Node::new(home("owner"), NodeConfig::default());
+    owner.pair(&relay_a, &token, "switch").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // B learned which relay the switch is on...
+    let presence = owner.locate(&relay_b, "switch").await.unwrap().unwrap();
+    assert_eq!(presence.relay_id52, key_a.public_key().to_bytes());
+
+    // ...and forwards SENDs there, preimage renewal included
+    for _ in 0..2 {
+        let status = owner.send(&relay_b, "switch", "status", json!({})).await.unwrap();
+        assert_eq!(status, json!({ "is_on": true }));
+    }
+}
+
+#[tokio::test]
+async fn hello_is_signed_by_the_relay_identity() {
+    let (relay_home, key) = relay_home("relay-identity");
+
+    let addr = free_addr();
+    let _relay = start_relay(&relay_home, &addr, None, "").await;
+
+    let conn = Connection::connect_anonymous(&format!("{addr}?id52={}", key.public_key())).await.unwrap();
+    assert_eq!(conn.relay_id52(), Some(key.public_key()));
+
+    let impostor = SecretKey::generate().public_key();
+    let err = Connection::connect_anonymous(&format!("{addr}?id52={impostor}")).await.err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[tokio::test]
+async fn relay_peer_from_an_unconfigured_relay_is_refused() {
+    let (relay_home, key) = relay_home("relay-stranger");
+    let (addr, tls_addr) = (free_addr(), free_addr());
+    let _relay = start_relay(&relay_home, &addr, Some(&tls_addr), "").await;
+
+    let relay = RelayAddr::parse(&format!("tls://{tls_addr}?id52={}", key.public_key())).unwrap();
+    let (reader, writer) = tokio::io::split(relay.connect().await.unwrap());
+    let mut reader = FramedRead::new(reader, BhumiCodec::new());
+    let mut writer = FramedWrite::new(writer, BhumiCodec::new());
+    let Ok(RelayMessage::Hello(hello)) = reader.next().await.unwrap().unwrap().decode() else {
+        panic!("expected HELLO");
+    };
+
+    // Correctly signed, but not a relay this one peers with
+    let stranger = SecretKey::generate();
+    writer.send(RelayMessage::from(RelayPeer::sign(hello.nonce, &stranger))).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(5), reader.next()).await;
+    assert!(matches!(closed, Ok(None | Some(Err(_)))));
+}
+
+#[tokio::test]
+async fn sends_are_answered_concurrently_on_one_connection() {
+    let addr = free_addr();
+    let _relay = start_relay(&home("relay-concurrent"), &addr, None, "").await;
+
+    let key = SecretKey::generate();
+    let id52 = key.public_key().to_bytes();
+    let preimages = [[1u8; 32], [2u8; 32]];
+    let commits = preimages.iter().map(|p| Sha256::digest(p).into()).collect();
+    let device = Connection::connect(&addr, &key, commits).await.unwrap();
+
+    // The device sends to itself on its own connection: both SENDs must be
+    // delivered before either is answered, and the answers (given in
+    // reverse) must reach the right SEND
+    let payload = |i: u8| vec![i; 128];
+    let receive = async {
+        let first = device.receive_deliver().await.unwrap();
+        let second = device.receive_deliver().await.unwrap();
+        for deliver in [second, first] {
+            device.send_ack(deliver.msg_id, deliver.payload).await.unwrap();
+        }
+    };
+    let (a, b, ()) = tokio::join!(
+        device.send(id52, preimages[0], payload(1)),
+        device.send(id52, preimages[1], payload(2)),
+        receive,
+    );
+    let (a, b) = (a.unwrap(), b.unwrap());
+    assert_eq!((a.status, a.payload), (SEND_OK, payload(1)));
+    assert_eq!((b.status, b.payload), (SEND_OK, payload(2)));
+}
@@ -1,6 +1,7 @@
 //! Blocking TCP connection for ESP32 (TLS disabled for dev)
 //!
-//! Uses std::net for blocking TCP I/O.
+//! Uses std::net for blocking TCP I/O and bhumi-proto's sans-IO
+//! `FrameDecoder`, so a read timeout never loses part of a frame.
 
 use fastn_id52::SecretKey;
 use bhumi_proto::*;
@@ -12,6 +13,7 @@ use log::*;
 pub struct ReceivedMessage {
     pub msg_id: u32,
     pub preimage: [u8; 32],
+    /// Decrypted payload
     pub payload: Vec<u8>,
     msg_type: Option<u8>,
 }
@@ -26,6 +28,9 @@ impl ReceivedMessage {
 /// Blocking TCP connection to bhumi relay
 pub struct Connection {
     stream: TcpStream,
+    decoder: FrameDecoder,
+    /// Capabilities from the relay's HELLO
+    relay_capabilities: u32,
 }
 
 impl Connection {
@@ -38,16 +43,18 @@ impl Connection {
         info!("Connecting to {}", addr);
 
         // Connect TCP
-        let mut stream = TcpStream::connect(addr)?;
+        let stream = TcpStream::connect(addr)?;
         info!("TCP connected");
+        let mut conn = Self { stream, decoder: FrameDecoder::new(), relay_capabilities: 0 };
 
         // Read HELLO
-        let frame = Frame::read_from(&mut stream)?;
-        if frame.msg_type != MSG_HELLO {
-            anyhow::bail!("expected HELLO, got msg_type {}", frame.msg_type);
-        }
-        let hello = Hello::from_bytes(&frame.payload)?;
+        let hello = match conn.read_frame()?.decode()? {
+            RelayMessage::Hello(hello) => hello,
+            other => anyhow::bail!("expected HELLO, got msg_type {}", other.msg_type()),
+        };
         info!("Received HELLO (nonce=0x{:08x})", hello.nonce);
+        conn.decoder.set_max_payload(hello.max_payload_size as usize);
+        conn.relay_capabilities = hello.capabilities;
 
         // Create I_AM response
         let mut to_sign = Vec::with_capacity(36);
@@ -59,63 +66,119 @@ impl Connection {
             secret_key.public_key().to_bytes(),
             signature.to_bytes(),
             commits,
-        );
+        )
+        .with_capabilities(CAP_KEEPALIVE | CAP_ENCRYPTION_REQUIRED);
 
         // Send I_AM
-        let frame = Frame::i_am(&i_am);
-        frame.write_to(&mut stream)?;
-        stream.flush()?;
-        info!("Sent I_AM, registered {} commits", i_am.commits.len());
+        let commit_count = i_am.commits.len();
+        conn.write_message(RelayMessage::IAm(i_am))?;
+        info!("Sent I_AM, registered {} commits", commit_count);
 
-        Ok(Self { stream })
+        Ok(conn)
     }
 
-    /// Receive a message (blocking, respects read timeout)
-    pub fn receive(&mut self) -> std::io::Result<ReceivedMessage> {
-        let frame = Frame::read_from(&mut self.stream)?;
-
-        if frame.msg_type != MSG_DELIVER {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("expected DELIVER, got msg_type {}", frame.msg_type),
-            ));
+    /// Receive and decrypt a message (blocking, respects read timeout)
+    ///
+    /// Payloads that are not encrypted to us are refused with a NACK.
+    pub fn receive(&mut self, secret_key: &SecretKey) -> std::io::Result<ReceivedMessage> {
+        loop {
+            let frame = self.read_frame()?;
+            let msg = frame.decode()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            let deliver = match msg {
+                RelayMessage::Deliver(deliver) => deliver,
+                // Relay's answer to our KEEPALIVE
+                RelayMessage::Keepalive => continue,
+                other => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("expected DELIVER, got msg_type {}", other.msg_type()),
+                    ));
+                }
+            };
+            info!("Received DELIVER msg_id={}", deliver.msg_id);
+
+            let payload = match crypto::open(&secret_key.to_bytes(), &deliver.payload) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("Refusing DELIVER msg_id={}: {}", deliver.msg_id, e);
+                    self.send_nack(deliver.msg_id, NACK_MALFORMED)
+                        .map_err(|e| std::io::Error::other(e.to_string()))?;
+                    continue;
+                }
+            };
+
+            // Check for handshake message type
+            let msg_type = payload.first().copied();
+
+            return Ok(ReceivedMessage {
+                msg_id: deliver.msg_id,
+                preimage: deliver.preimage,
+                payload,
+                msg_type,
+            });
         }
-
-        let deliver = Deliver::from_bytes(&frame.payload)?;
-        info!("Received DELIVER msg_id={}", deliver.msg_id);
-
-        // Check for handshake message type
-        let msg_type = if !deliver.payload.is_empty() {
-            Some(deliver.payload[0])
-        } else {
-            None
-        };
-
-        Ok(ReceivedMessage {
-            msg_id: deliver.msg_id,
-            preimage: deliver.preimage,
-            payload: deliver.payload,
-            msg_type,
-        })
     }
 
     /// Send ACK response
     pub fn send_ack(&mut self, msg_id: u32, payload: Vec<u8>) -> anyhow::Result<()> {
-        let ack = Ack { msg_id, payload };
-        let frame = Frame::ack(&ack);
-        frame.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.write_message(RelayMessage::Ack(Ack { msg_id, payload }))?;
         info!("Sent ACK msg_id={}", msg_id);
         Ok(())
     }
 
+    /// Refuse a delivered message with a NACK_* reason
+    ///
+    /// Relays without CAP_NACK get an empty ACK instead, which their sender
+    /// receives as an empty response.
+    pub fn send_nack(&mut self, msg_id: u32, reason: u8) -> anyhow::Result<()> {
+        if self.relay_capabilities & CAP_NACK == 0 {
+            return self.send_ack(msg_id, Vec::new());
+        }
+        self.write_message(RelayMessage::Nack(Nack { msg_id, reason }))?;
+        info!("Sent NACK msg_id={} reason={}", msg_id, reason);
+        Ok(())
+    }
+
     /// Update commits with relay
     pub fn update_commits(&mut self, commits: Vec<[u8; 32]>) -> anyhow::Result<()> {
-        let update = UpdateCommits { commits };
-        let frame = Frame::update_commits(&update);
-        frame.write_to(&mut self.stream)?;
+        let count = commits.len();
+        self.write_message(RelayMessage::UpdateCommits(UpdateCommits::new(commits)))?;
+        info!("Sent UPDATE_COMMITS ({} commits)", count);
+        Ok(())
+    }
+
+    /// Send KEEPALIVE so the relay doesn't drop us as idle (no-op on relays
+    /// that don't support it)
+    pub fn send_keepalive(&mut self) -> anyhow::Result<()> {
+        if self.relay_capabilities & CAP_KEEPALIVE == 0 {
+            return Ok(());
+        }
+        self.write_message(RelayMessage::Keepalive)
+    }
+
+    /// Read until the decoder has a complete frame (blocking, respects read timeout)
+    ///
+    /// Bytes read before a timeout stay buffered in the decoder.
+    fn read_frame(&mut self) -> std::io::Result<Frame> {
+        let mut buf = [0u8; 512];
+        loop {
+            if let Some(frame) = self.decoder.next_frame()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+            {
+                return Ok(frame);
+            }
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.decoder.feed(&buf[..n]);
+        }
+    }
+
+    fn write_message(&mut self, msg: RelayMessage) -> anyhow::Result<()> {
+        self.stream.write_all(&msg.encode()?.to_bytes()?)?;
         self.stream.flush()?;
-        info!("Sent UPDATE_COMMITS ({} commits)", update.commits.len());
         Ok(())
     }
 
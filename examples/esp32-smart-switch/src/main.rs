@@ -24,6 +24,9 @@ use std::sync::{atomic::AtomicBool, Arc, Mutex};
 
 const RELAY_ADDR: &str = "64.227.143.197:8443";
 
+/// Relay drops sessions idle for 90s; ping well within that
+const KEEPALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
 // Switch state
 static IS_ON: AtomicBool = AtomicBool::new(false);
 
@@ -297,6 +300,7 @@ fn run_connection(
 
     // Set a shorter read timeout so we can check BLE commands
     conn.set_read_timeout(Some(std::time::Duration::from_millis(500)))?;
+    let mut last_keepalive = std::time::Instant::now();
 
     loop {
         // Check for BLE commands
@@ -306,7 +310,7 @@ fn run_connection(
         }
 
         // Try to receive a message (with timeout)
-        match conn.receive() {
+        match conn.receive(device_state.secret_key()) {
             Ok(msg) => {
                 // Check if it's a handshake or command
                 if msg.is_handshake() {
@@ -316,24 +320,25 @@ fn run_connection(
                             conn.update_commits(vec![commit])?;
                         }
                     } else {
-                        conn.send_ack(msg.msg_id, device_state.reject_handshake())?;
+                        conn.send_ack(msg.msg_id, device_state.reject_handshake(&msg))?;
                     }
                 } else {
-                    // Handle command
+                    // Handle command (response is encrypted, new preimage included)
                     let (response, new_commit) = switch::handle_command(device_state, &msg);
-                    let mut response_bytes = response;
 
-                    // Append new preimage if available
-                    if let Some((new_preimage, commit)) = new_commit {
-                        response_bytes.extend_from_slice(&new_preimage);
+                    if let Some(commit) = new_commit {
                         conn.update_commits(vec![commit])?;
                     }
 
-                    conn.send_ack(msg.msg_id, response_bytes)?;
+                    conn.send_ack(msg.msg_id, response)?;
                 }
             }
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
-                // Timeout - loop back and check BLE
+                // Timeout - ping the relay if due, then loop back and check BLE
+                if last_keepalive.elapsed() >= KEEPALIVE_INTERVAL {
+                    conn.send_keepalive()?;
+                    last_keepalive = std::time::Instant::now();
+                }
                 continue;
             }
             Err(e) => {
@@ -4,7 +4,7 @@ use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
 use fastn_id52::{SecretKey, PublicKey};
 use sha2::{Sha256, Digest};
 use serde::{Serialize, Deserialize};
-use bhumi_proto::{HandshakeInit, HandshakeComplete, HANDSHAKE_ACCEPTED, HANDSHAKE_REJECTED};
+use bhumi_proto::{crypto, HandshakeInit, HandshakeComplete, HANDSHAKE_ACCEPTED, HANDSHAKE_REJECTED};
 use data_encoding::BASE64URL_NOPAD;
 use log::*;
 
@@ -131,9 +131,10 @@ impl DeviceState {
         create_invite_token(&self.public_key.to_bytes(), &preimage)
     }
 
-    /// Handle incoming handshake, returns (response_bytes, new_commit)
+    /// Handle incoming handshake, returns (encrypted response, new_commit)
     pub fn handle_handshake(&mut self, msg: &super::connection::ReceivedMessage) -> Option<(Vec<u8>, Option<[u8; 32]>)> {
         let init = HandshakeInit::from_bytes(&msg.payload).ok()?;
+        let peer_id52 = init.sender_id52;
 
         // Find the invite by preimage
         let invite_idx = self.state.invites.iter()
@@ -163,16 +164,23 @@ impl DeviceState {
         };
 
         let new_commit = sha256(&our_preimage);
-        Some((complete.to_bytes(), Some(new_commit)))
+        let response = crypto::seal_with_rng(&mut rand::rngs::OsRng, &peer_id52, &complete.to_bytes().ok()?).ok()?;
+        Some((response, Some(new_commit)))
     }
 
-    /// Generate rejection response
-    pub fn reject_handshake(&self) -> Vec<u8> {
-        HandshakeComplete {
+    /// Generate encrypted rejection response (empty if the sender is unknown)
+    pub fn reject_handshake(&self, msg: &super::connection::ReceivedMessage) -> Vec<u8> {
+        let Ok(init) = HandshakeInit::from_bytes(&msg.payload) else {
+            return Vec::new();
+        };
+        let complete = HandshakeComplete {
             status: HANDSHAKE_REJECTED,
             preimage_for_peer: [0u8; 32],
             relay_url: String::new(),
-        }.to_bytes()
+        };
+        complete.to_bytes().ok()
+            .and_then(|bytes| crypto::seal_with_rng(&mut rand::rngs::OsRng, &init.sender_id52, &bytes).ok())
+            .unwrap_or_default()
     }
 
     /// Look up peer by preimage commit
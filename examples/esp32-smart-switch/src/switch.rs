@@ -36,18 +36,15 @@ impl Response {
 }
 
 /// Handle an incoming command
-/// Returns (response_bytes, Option<(new_preimage, new_commit)>)
+/// Returns (encrypted response, Option<new_commit>)
 pub fn handle_command(
     state: &mut DeviceState,
     msg: &super::connection::ReceivedMessage,
-) -> (Vec<u8>, Option<([u8; 32], [u8; 32])>) {
-    // Look up sender by preimage
+) -> (Vec<u8>, Option<[u8; 32]>) {
+    // Look up sender by preimage (unknown sender: nobody to encrypt a response to)
     let (peer_id52, peer) = match state.lookup_preimage(&msg.preimage) {
         Some((id, p)) => (id.clone(), p.clone()),
-        None => {
-            let response = Response::err("unauthorized");
-            return (serde_json::to_vec(&response).unwrap(), None);
-        }
+        None => return (Vec::new(), None),
     };
 
     // Parse request
@@ -55,7 +52,7 @@ pub fn handle_command(
         Ok(r) => r,
         Err(e) => {
             let response = Response::err(format!("invalid request: {}", e));
-            return (serde_json::to_vec(&response).unwrap(), None);
+            return (seal(&peer_id52, serde_json::to_vec(&response).unwrap()), None);
         }
     };
 
@@ -71,12 +68,21 @@ pub fn handle_command(
     }
 
     // Serialize response
-    let response_bytes = serde_json::to_vec(&response).unwrap();
+    let mut response_bytes = serde_json::to_vec(&response).unwrap();
+
+    // Renew preimage, appended to the response
+    let new_commit = state.renew_preimage(&peer_id52, &msg.preimage)
+        .map(|(new_preimage, commit)| {
+            response_bytes.extend_from_slice(&new_preimage);
+            commit
+        });
 
-    // Renew preimage
-    let new_preimage = state.renew_preimage(&peer_id52, &msg.preimage);
+    (seal(&peer_id52, response_bytes), new_commit)
+}
 
-    (response_bytes, new_preimage)
+/// Encrypt a response for the peer
+fn seal(peer_id52: &[u8; 32], plaintext: Vec<u8>) -> Vec<u8> {
+    bhumi_proto::crypto::seal_with_rng(&mut rand::rngs::OsRng, peer_id52, &plaintext).unwrap_or_default()
 }
 
 fn dispatch_command(state: &DeviceState, role: &PeerRole, req: &Request) -> Response {